@@ -9,6 +9,7 @@ pub mod pty;
 
 use server::{Server, ServerConfig};
 use std::env;
+use std::time::Duration;
 
 const SERVER_VERSION: &str = match option_env!("TERMINAL_SERVER_VERSION") {
     Some(version) => version,
@@ -30,27 +31,128 @@ macro_rules! log_debug {
     };
 }
 
+/// Install the tracing subscriber used by the PTY module
+///
+/// Logs go to stderr because stdout carries the port handshake. `RUST_LOG` overrides
+/// the default level, which matches the old behavior of printing debug logs only in
+/// debug builds.
+fn init_tracing() {
+    let default_level = if cfg!(debug_assertions) { "debug" } else { "info" };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default_level));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .init();
+}
+
 /// Parse command-line arguments
-fn parse_args() -> u16 {
+fn parse_args() -> ServerConfig {
     let args: Vec<String> = env::args().collect();
-    let mut port: u16 = 0;
+    let mut config = ServerConfig::default();
 
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
-            "-p" | "--port" => {
-                if i + 1 < args.len() {
-                    port = args[i + 1].parse().unwrap_or(0);
-                    i += 1;
-                }
+            "-p" | "--port" if i + 1 < args.len() => {
+                config.port = args[i + 1].parse().unwrap_or(0);
+                i += 1;
             }
             arg if arg.starts_with("--port=") => {
-                port = arg.trim_start_matches("--port=").parse().unwrap_or(0);
+                config.port = arg.trim_start_matches("--port=").parse().unwrap_or(0);
+            }
+            "--resize-debounce-ms" if i + 1 < args.len() => {
+                if let Ok(ms) = args[i + 1].parse() {
+                    config.pty.resize_debounce = Duration::from_millis(ms);
+                }
+                i += 1;
+            }
+            "--ping-interval-secs" if i + 1 < args.len() => {
+                if let Ok(secs) = args[i + 1].parse() {
+                    config.ping_interval = Duration::from_secs(secs);
+                }
+                i += 1;
+            }
+            "--inheritable-fd" if i + 1 < args.len() => {
+                if let Ok(fd) = args[i + 1].parse() {
+                    config.pty.inheritable_fds.push(fd);
+                }
+                i += 1;
+            }
+            "--tap-prefix" if i + 1 < args.len() => {
+                config.pty.tap_prefix = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--record-dir" if i + 1 < args.len() => {
+                config.pty.record_dir = Some(std::path::PathBuf::from(&args[i + 1]));
+                i += 1;
+            }
+            "--max-sessions" if i + 1 < args.len() => {
+                if let Ok(count) = args[i + 1].parse() {
+                    config.pty.max_sessions = Some(count);
+                }
+                i += 1;
+            }
+            "--max-sessions-per-connection" if i + 1 < args.len() => {
+                if let Ok(count) = args[i + 1].parse() {
+                    config.pty.max_sessions_per_connection = Some(count);
+                }
+                i += 1;
+            }
+            "--explicit-sigwinch" => {
+                config.pty.explicit_sigwinch = true;
+            }
+            "--memory-cgroup" if i + 1 < args.len() => {
+                config.pty.memory_cgroup = Some(std::path::PathBuf::from(&args[i + 1]));
+                i += 1;
+            }
+            "--windows-shell" if i + 1 < args.len() => {
+                if let Some(preference) = pty::WindowsShellPreference::parse(&args[i + 1]) {
+                    config.pty.windows_shell = preference;
+                }
+                i += 1;
+            }
+            "--require-absolute-cwd" => {
+                config.pty.require_absolute_cwd = true;
+            }
+            "--allowed-shell" if i + 1 < args.len() => {
+                config.pty.allowed_shells.get_or_insert_with(Vec::new).push(args[i + 1].clone());
+                i += 1;
+            }
+            "--allow-run-as" if i + 1 < args.len() => {
+                config.pty.allowed_run_as.get_or_insert_with(Vec::new).push(args[i + 1].clone());
+                i += 1;
+            }
+            "--audit-log" if i + 1 < args.len() => {
+                config.pty.audit_log = Some(std::path::PathBuf::from(&args[i + 1]));
+                i += 1;
+            }
+            "--max-missed-pongs" if i + 1 < args.len() => {
+                if let Ok(count) = args[i + 1].parse() {
+                    config.max_missed_pongs = count;
+                }
+                i += 1;
             }
             "-h" | "--help" => {
                 eprintln!("Usage: termy-server [OPTIONS]");
                 eprintln!("Options:");
                 eprintln!("  -p, --port <PORT>         监听端口 (0 表示随机端口) [默认: 0]");
+                eprintln!("      --resize-debounce-ms <MS>  resize 防抖窗口 (0 表示立即生效) [默认: 16]");
+                eprintln!("      --ping-interval-secs <SECS>  心跳 Ping 间隔 (0 表示禁用) [默认: 20]");
+                eprintln!("      --max-missed-pongs <N>     允许连续丢失的 Pong 次数 [默认: 3]");
+                eprintln!("      --allowed-shell <SHELL>    只允许启动的 shell_type 或程序路径 (可重复，默认不限制)");
+                eprintln!("      --allow-run-as <USER>      init 可通过 uid/gid/username 切换到的用户名或 uid (仅 Unix，可重复，默认禁止切换)");
+                eprintln!("      --audit-log <FILE>         将所有会话的 write_data 输入追加到此文件 (每行一个 JSON，默认不记录)");
+                eprintln!("      --inheritable-fd <FD>      允许 init 通过 inherit_fd 交给 shell 的描述符 (仅 Unix，可重复)");
+                eprintln!("      --tap-prefix <PREFIX>      init 的 tap_socket 路径必须以此开头 (默认禁用输出 tap)");
+                eprintln!("      --record-dir <DIR>         init 的 record_path 录制文件只能创建在此目录内 (默认禁用录制)");
+                eprintln!("      --max-sessions <N>         服务器最多运行的会话数 (默认不限制)");
+                eprintln!("      --max-sessions-per-connection <N>  每个连接最多持有的会话数 (默认不限制)");
+                eprintln!("      --explicit-sigwinch        每次调整尺寸后向前台进程组发送 SIGWINCH (仅 Unix，用于内核未通知的环境)");
+                eprintln!("      --memory-cgroup <DIR>      memory_limit_bytes 的会话 cgroup 创建在此 cgroup v2 目录下 (仅 Linux，默认为服务器自身的 cgroup)");
+                eprintln!("      --windows-shell <MODE>     未设置 SHELL 时的默认 shell: powershell 优先 5.x，pwsh 优先 PowerShell 7，terminal 跟随 Windows Terminal 默认配置 (仅 Windows) [默认: powershell]");
+                eprintln!("      --require-absolute-cwd     init 必须提供绝对路径的 cwd，不再默认使用服务器的工作目录");
                 eprintln!("  -h, --help                显示帮助信息");
                 eprintln!("  -V, --version             显示版本信息");
                 std::process::exit(0);
@@ -64,17 +166,19 @@ fn parse_args() -> u16 {
         i += 1;
     }
 
-    port
+    config
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse command-line arguments
-    let port = parse_args();
-    log_debug!("启动参数: port={}", port);
+    // The server doubles as the helpers that start shells as another user, with an extra
+    // descriptor or in a memory cgroup
+    pty::run_helper_if_requested();
 
-    // Create the server configuration
-    let config = ServerConfig { port };
+    // Parse command-line arguments and create the server configuration
+    let config = parse_args();
+    init_tracing();
+    log_debug!("启动参数: port={}, pty={:?}", config.port, config.pty);
 
     // Create and start the server
     let server = Server::new(config);
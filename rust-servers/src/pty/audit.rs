@@ -0,0 +1,78 @@
+// Input audit log
+// Appends every write_data payload of a session to a file, one JSON object per line:
+// {"time_ms": ..., "session_id": ..., "data": ...}. Separate from the output recording.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Audit log of one session
+pub struct AuditLog {
+    file: File,
+    session_id: String,
+}
+
+impl AuditLog {
+    /// Open the file for appending, creating it if needed; existing entries are kept
+    pub fn open(path: &Path, session_id: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self { file, session_id: session_id.to_string() })
+    }
+
+    /// Append one input payload
+    ///
+    /// The line goes to the file in a single unbuffered write, so it is on disk (or in the
+    /// OS cache) before the input reaches the shell. Input that is not valid UTF-8 is
+    /// logged as `data_hex` instead of `data`, so no byte is lost or altered.
+    pub fn input(&mut self, data: &[u8]) -> io::Result<()> {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        let mut entry = serde_json::json!({ "time_ms": time_ms, "session_id": self.session_id });
+        match std::str::from_utf8(data) {
+            Ok(text) => entry["data"] = serde_json::json!(text),
+            Err(_) => entry["data_hex"] = serde_json::json!(hex(data)),
+        }
+        let mut line = entry.to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_appends_entries_in_order() {
+        let path = std::env::temp_dir().join(format!("termy-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut log = AuditLog::open(&path, "one").unwrap();
+        log.input(b"ls\r").unwrap();
+        log.input(&[0xff, 0x00]).unwrap();
+        drop(log);
+        // Reopening appends rather than truncating
+        AuditLog::open(&path, "two").unwrap().input(b"exit\r").unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["session_id"], "one");
+        assert_eq!(lines[0]["data"], "ls\r");
+        assert_eq!(lines[1]["data_hex"], "ff00");
+        assert!(lines[1].get("data").is_none());
+        assert_eq!(lines[2]["session_id"], "two");
+        assert_eq!(lines[2]["data"], "exit\r");
+        assert!(lines[0]["time_ms"].as_u64().unwrap() <= lines[2]["time_ms"].as_u64().unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+}
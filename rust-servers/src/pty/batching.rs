@@ -0,0 +1,113 @@
+// Output batching
+// How long the read task keeps collecting output into one frame before sending it
+
+use tokio::time::{Duration, Instant};
+
+/// Window of the default, fixed batching
+pub const FIXED_INTERVAL: Duration = Duration::from_millis(4);
+
+/// Longest window adaptive batching grows to under sustained output
+pub const MAX_ADAPTIVE_WINDOW: Duration = Duration::from_millis(16);
+
+/// Shortest non-zero adaptive window; shrinking below it means flushing right away
+const MIN_ADAPTIVE_WINDOW: Duration = Duration::from_millis(1);
+
+/// A batch at least this large means output is sustained and the window may grow
+const BUSY_BATCH_BYTES: usize = 4 * 1024;
+
+/// After a pause this long, output is interactive again and is flushed right away
+const IDLE_GAP: Duration = Duration::from_millis(20);
+
+/// Batching window of one session
+///
+/// Fixed batching waits [`FIXED_INTERVAL`] after the first output of every batch.
+/// Adaptive batching sends sparse output (typing echo, a prompt) without waiting, and
+/// doubles the window while batches stay large, up to [`MAX_ADAPTIVE_WINDOW`]; smaller
+/// batches halve it again, and a pause resets it.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchWindow {
+    adaptive: bool,
+    window: Duration,
+    last_batch: Option<Instant>,
+}
+
+impl BatchWindow {
+    pub fn fixed() -> Self {
+        Self { adaptive: false, window: FIXED_INTERVAL, last_batch: None }
+    }
+
+    pub fn adaptive() -> Self {
+        Self { adaptive: true, window: Duration::ZERO, last_batch: None }
+    }
+
+    /// How long the batch whose first output arrived at `now` may collect more output
+    ///
+    /// A zero window still takes the output that is already queued.
+    pub fn start(&mut self, now: Instant) -> Duration {
+        if self.adaptive && self.last_batch.is_some_and(|last| now.duration_since(last) >= IDLE_GAP) {
+            self.window = Duration::ZERO;
+        }
+        self.window
+    }
+
+    /// Record a batch of `bytes` that was completed at `now`
+    pub fn finish(&mut self, bytes: usize, now: Instant) {
+        self.last_batch = Some(now);
+        if !self.adaptive {
+            return;
+        }
+        self.window = if bytes >= BUSY_BATCH_BYTES {
+            (self.window * 2).clamp(MIN_ADAPTIVE_WINDOW, MAX_ADAPTIVE_WINDOW)
+        } else if self.window / 2 < MIN_ADAPTIVE_WINDOW {
+            Duration::ZERO
+        } else {
+            self.window / 2
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_window_never_changes() {
+        let mut batching = BatchWindow::fixed();
+        let now = Instant::now();
+        for bytes in [1, 64 * 1024, 1] {
+            assert_eq!(batching.start(now), FIXED_INTERVAL);
+            batching.finish(bytes, now);
+        }
+    }
+
+    #[test]
+    fn test_sparse_output_is_sent_right_away() {
+        let mut batching = BatchWindow::adaptive();
+        let mut now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(batching.start(now), Duration::ZERO);
+            batching.finish(1, now);
+            now += Duration::from_millis(5);
+        }
+    }
+
+    #[test]
+    fn test_sustained_output_grows_window_up_to_cap() {
+        let mut batching = BatchWindow::adaptive();
+        let mut now = Instant::now();
+        let mut windows = Vec::new();
+        for _ in 0..8 {
+            let window = batching.start(now);
+            windows.push(window.as_millis());
+            now += window;
+            batching.finish(BUSY_BATCH_BYTES, now);
+        }
+        assert_eq!(windows, [0, 1, 2, 4, 8, 16, 16, 16]);
+
+        // Output tapering off shrinks the window again, a pause resets it
+        batching.finish(1, now);
+        assert_eq!(batching.start(now), Duration::from_millis(8));
+        batching.finish(BUSY_BATCH_BYTES, now);
+        assert_eq!(batching.start(now + IDLE_GAP), Duration::ZERO);
+    }
+}
@@ -0,0 +1,145 @@
+// Clean text for logging consumers
+// Output with carriage returns removed, decoded as text; never used for the terminal stream
+
+use super::frame;
+
+/// Turns raw PTY output into newline-normalized text
+///
+/// `\r\n` becomes `\n` and bare `\r` is dropped, which together means every `\r` goes.
+/// In-place updates (progress bars) therefore end up concatenated on one line; that is
+/// fine for a log and the reason this must never feed the live terminal.
+#[derive(Debug, Default)]
+pub struct CleanText {
+    /// Partial UTF-8 character held back until the next chunk
+    carry: Vec<u8>,
+    /// End lines with `\r\n` instead of `\n`, for logs read on Windows
+    crlf: bool,
+}
+
+impl CleanText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like [`new`](Self::new), but every line ends with `\r\n`
+    pub fn crlf() -> Self {
+        Self { crlf: true, ..Self::default() }
+    }
+
+    /// Normalize a chunk of output; a character split across chunks is returned whole later
+    pub fn feed(&mut self, data: &[u8]) -> String {
+        self.carry.extend(data.iter().filter(|&&b| b != b'\r'));
+        let tail = frame::incomplete_utf8_tail(&self.carry);
+        let rest = self.carry.split_off(self.carry.len() - tail);
+        let mut text = String::from_utf8_lossy(&self.carry).into_owned();
+        self.carry = rest;
+        if self.crlf {
+            text = text.replace('\n', "\r\n");
+        }
+        text
+    }
+}
+
+/// Where an [`EscapeFilter`] is within an escape sequence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Escape {
+    #[default]
+    None,
+    /// After ESC
+    Start,
+    /// After ESC and an intermediate byte, as in `ESC ( B`, until the final byte
+    Intermediate,
+    /// In a CSI sequence, until its final byte
+    Csi,
+    /// In an OSC or other string sequence, until BEL or ST
+    String,
+    /// ESC inside a string sequence, possibly starting ST
+    StringEsc,
+}
+
+/// Separates escape sequences from text, one byte at a time
+///
+/// Handles CSI, OSC and the other string sequences, and plain ESC sequences; that is
+/// enough for prompts and error messages, not for a terminal emulator.
+#[derive(Debug, Default)]
+pub struct EscapeFilter {
+    escape: Escape,
+}
+
+impl EscapeFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `byte` is text rather than part of an escape sequence; control bytes
+    /// outside sequences count as text
+    pub fn is_text(&mut self, byte: u8) -> bool {
+        let (next, text) = match (self.escape, byte) {
+            (Escape::None, 0x1b) => (Escape::Start, false),
+            (Escape::None, _) => (Escape::None, true),
+            (Escape::Start, b'[') => (Escape::Csi, false),
+            (Escape::Start, b']' | b'P' | b'X' | b'^' | b'_') => (Escape::String, false),
+            (Escape::Start | Escape::Intermediate, 0x20..=0x2f) => (Escape::Intermediate, false),
+            (Escape::Start | Escape::Intermediate, _) => (Escape::None, false),
+            (Escape::Csi, 0x40..=0x7e) => (Escape::None, false),
+            (Escape::Csi, _) => (Escape::Csi, false),
+            (Escape::String, 0x07) => (Escape::None, false),
+            (Escape::String, 0x1b) => (Escape::StringEsc, false),
+            (Escape::String, _) => (Escape::String, false),
+            (Escape::StringEsc, b'\\') => (Escape::None, false),
+            (Escape::StringEsc, _) => (Escape::String, false),
+        };
+        self.escape = next;
+        text
+    }
+}
+
+/// Output as readable text for an event payload: escape sequences and control characters
+/// other than newline and tab removed, `\r\n` turned into `\n`
+pub fn readable(data: &[u8]) -> String {
+    let mut filter = EscapeFilter::new();
+    let text: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|&byte| filter.is_text(byte) && (byte >= 0x20 || byte == b'\n' || byte == b'\t') && byte != 0x7f)
+        .collect();
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalizes_carriage_returns() {
+        let mut clean = CleanText::new();
+        assert_eq!(clean.feed(b"one\r\ntwo\r\n"), "one\ntwo\n");
+        assert_eq!(clean.feed(b"10%\r20%\r\n"), "10%20%\n");
+        // \r\n split across chunks
+        assert_eq!(clean.feed(b"three\r"), "three");
+        assert_eq!(clean.feed(b"\nfour"), "\nfour");
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        let mut clean = CleanText::crlf();
+        assert_eq!(clean.feed(b"one\ntwo\r\n"), "one\r\ntwo\r\n");
+        assert_eq!(clean.feed(b"10%\r20%\r"), "10%20%");
+        assert_eq!(clean.feed(b"\n"), "\r\n");
+    }
+
+    #[test]
+    fn test_keeps_split_characters_whole() {
+        let mut clean = CleanText::new();
+        let text = "中文".as_bytes();
+        assert_eq!(clean.feed(&text[..4]), "中");
+        assert_eq!(clean.feed(&text[4..]), "文");
+    }
+
+    #[test]
+    fn test_readable_strips_escapes_and_controls() {
+        let output = b"\x1b]0;title\x07\x1b[1;31merror:\x1b[0m bad option\r\n\x1b(Bline\x08\ttwo\x1b]8;;x\x1b\\\r\n";
+        assert_eq!(readable(output), "error: bad option\nline\ttwo\n");
+        assert_eq!(readable("中文\x07".as_bytes()), "中文");
+    }
+}
@@ -0,0 +1,253 @@
+// Running the shell as another user (Unix only)
+// The shell is started through the server's run-as helper (see reexec), which drops
+// privileges before it execs the shell.
+
+use super::reexec::Helper;
+use portable_pty::CommandBuilder;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// `termy-server --run-as <uid> <gid> <user> -- <argv...>`; an empty user sets no
+/// supplementary groups beyond the gid
+pub const HELPER: Helper = Helper { arg: "--run-as", usage: "<uid> <gid> <user>", run: run_helper };
+
+/// Identity requested by init; any combination of the fields may be given
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunAs {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub username: Option<String>,
+}
+
+impl RunAs {
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.username.is_none()
+    }
+}
+
+/// Credentials the shell is started with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Account name from the password database; supplementary groups come from it
+    pub user: Option<String>,
+    /// Home directory from the password database
+    pub home: Option<PathBuf>,
+    /// Binary run as the helper, the server itself
+    pub helper: PathBuf,
+}
+
+/// Password database entry
+#[cfg(unix)]
+struct Passwd {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: PathBuf,
+}
+
+#[cfg(unix)]
+enum PasswdKey<'a> {
+    Name(&'a str),
+    Uid(u32),
+}
+
+#[cfg(unix)]
+fn lookup_passwd(key: PasswdKey) -> Result<Option<Passwd>, String> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        let ret = match &key {
+            PasswdKey::Name(name) => {
+                let name = CString::new(*name).map_err(|_| "username contains a NUL byte".to_string())?;
+                unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) }
+            }
+            PasswdKey::Uid(uid) => unsafe {
+                libc::getpwuid_r(*uid as libc::uid_t, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+            },
+        };
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(format!("password database lookup failed: {}", std::io::Error::from_raw_os_error(ret)));
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+        let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+        let home = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        return Ok(Some(Passwd {
+            name: name.to_string_lossy().into_owned(),
+            uid: pwd.pw_uid as u32,
+            gid: pwd.pw_gid as u32,
+            home: PathBuf::from(std::ffi::OsStr::from_bytes(home.to_bytes())),
+        }));
+    }
+}
+
+/// Resolve the requested identity against the password database
+///
+/// A username supplies uid, primary gid and supplementary groups; an explicit uid or gid
+/// must agree with it. A bare uid that has no account needs an explicit gid.
+#[cfg(unix)]
+pub fn resolve(run_as: &RunAs) -> Result<Credentials, String> {
+    let entry = match (&run_as.username, run_as.uid) {
+        (Some(name), uid) => {
+            let entry = lookup_passwd(PasswdKey::Name(name))?.ok_or_else(|| format!("unknown user: {}", name))?;
+            if uid.is_some_and(|uid| uid != entry.uid) {
+                return Err(format!("uid does not match user {}", name));
+            }
+            Some(entry)
+        }
+        (None, Some(uid)) => lookup_passwd(PasswdKey::Uid(uid))?,
+        (None, None) => None,
+    };
+
+    let uid = match (&entry, run_as.uid) {
+        (Some(entry), _) => entry.uid,
+        (None, Some(uid)) => uid,
+        // Only a group was requested: keep the current user
+        (None, None) => unsafe { libc::getuid() as u32 },
+    };
+    let gid = match (run_as.gid, &entry) {
+        (Some(gid), _) => gid,
+        (None, Some(entry)) => entry.gid,
+        (None, None) if run_as.uid.is_none() => unsafe { libc::getgid() as u32 },
+        (None, None) => return Err(format!("uid {} has no account; gid is required", uid)),
+    };
+    let helper = std::env::current_exe().map_err(|e| format!("cannot locate the server binary: {}", e))?;
+
+    Ok(Credentials {
+        uid,
+        gid,
+        user: entry.as_ref().map(|e| e.name.clone()),
+        home: entry.map(|e| e.home),
+        helper,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn resolve(_run_as: &RunAs) -> Result<Credentials, String> {
+    Err("uid/gid/username are only supported on Unix".to_string())
+}
+
+impl Credentials {
+    /// Whether the server already runs with exactly these credentials
+    #[cfg(unix)]
+    pub fn is_current(&self) -> bool {
+        unsafe { libc::geteuid() as u32 == self.uid && libc::getegid() as u32 == self.gid }
+    }
+
+    /// Switching to other credentials needs root
+    #[cfg(unix)]
+    pub fn check_privilege(&self) -> Result<(), String> {
+        if self.is_current() || unsafe { libc::geteuid() } == 0 {
+            Ok(())
+        } else {
+            Err(format!(
+                "running the shell as uid {} gid {} requires the server to run as root",
+                self.uid, self.gid
+            ))
+        }
+    }
+
+    /// Wrap `cmd` so that the helper switches credentials before exec'ing it
+    pub fn wrap(&self, cmd: &CommandBuilder) -> CommandBuilder {
+        let user = self.user.clone().unwrap_or_default();
+        HELPER.wrap(&self.helper, [self.uid.to_string(), self.gid.to_string(), user], cmd)
+    }
+}
+
+/// Drop to the requested credentials and exec the shell; only returns on failure
+#[cfg(unix)]
+fn run_helper(args: &[OsString], argv: &[OsString]) -> String {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let [uid, gid, user] = args else {
+        return HELPER.usage();
+    };
+    let (Some(uid), Some(gid)) = (
+        uid.to_str().and_then(|s| s.parse::<libc::uid_t>().ok()),
+        gid.to_str().and_then(|s| s.parse::<libc::gid_t>().ok()),
+    ) else {
+        return "invalid uid or gid".to_string();
+    };
+
+    // Groups first: once the uid is dropped the process may no longer change them
+    let ret = if user.is_empty() {
+        unsafe { libc::setgroups(1, &gid) }
+    } else {
+        match CString::new(user.as_bytes()) {
+            Ok(name) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+            Err(_) => return "user name contains a NUL byte".to_string(),
+        }
+    };
+    if ret != 0 {
+        return format!("setting supplementary groups failed: {}", std::io::Error::last_os_error());
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return format!("setgid({}) failed: {}", gid, std::io::Error::last_os_error());
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return format!("setuid({}) failed: {}", uid, std::io::Error::last_os_error());
+    }
+    // A dropped root must not be able to get back
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return "privileges could not be dropped".to_string();
+    }
+
+    super::reexec::exec(argv)
+}
+
+#[cfg(not(unix))]
+fn run_helper(_args: &[OsString], _argv: &[OsString]) -> String {
+    "only supported on Unix".to_string()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_username() {
+        let creds = resolve(&RunAs { username: Some("root".to_string()), ..Default::default() }).unwrap();
+        assert_eq!((creds.uid, creds.gid), (0, 0));
+        assert_eq!(creds.user.as_deref(), Some("root"));
+
+        let mismatch = RunAs { uid: Some(1), username: Some("root".to_string()), ..Default::default() };
+        assert!(resolve(&mismatch).is_err());
+        assert!(resolve(&RunAs { username: Some("termy-no-such-user".to_string()), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_resolve_bare_ids() {
+        // An id with no account needs its group spelled out
+        let unknown = 4_000_000_000;
+        assert!(resolve(&RunAs { uid: Some(unknown), ..Default::default() }).is_err());
+        let creds = resolve(&RunAs { uid: Some(unknown), gid: Some(unknown), ..Default::default() }).unwrap();
+        assert_eq!((creds.uid, creds.gid, creds.user), (unknown, unknown, None));
+    }
+
+    #[test]
+    fn test_wrap_passes_argv_after_separator() {
+        let creds = Credentials {
+            uid: 65534,
+            gid: 65534,
+            user: None,
+            home: None,
+            helper: PathBuf::from("/srv/termy-server"),
+        };
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.arg("-l");
+        let argv: Vec<_> = creds.wrap(&cmd).get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(argv, ["/srv/termy-server", "--run-as", "65534", "65534", "", "--", "bash", "-l"]);
+    }
+}
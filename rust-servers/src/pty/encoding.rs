@@ -0,0 +1,115 @@
+// Output encoding hints
+// The server always forwards raw bytes; this only tells the client which decoder is
+// likely right, from the init `encoding` hint or from output that is not valid UTF-8.
+
+/// Longest accepted encoding label
+const MAX_ENCODING_LEN: usize = 32;
+
+/// Output batches with invalid UTF-8 before the stream is reported as probably not UTF-8
+///
+/// A single invalid batch is common (a binary file printed by mistake), so one is not enough.
+pub const INVALID_BATCHES_BEFORE_WARNING: u32 = 3;
+
+/// Validate an encoding label such as `utf-8`, `gbk` or `shift_jis`; empty means none
+///
+/// The label is not checked against a list, the client's decoder decides what it supports.
+pub fn validate_encoding(label: &str) -> Result<Option<String>, String> {
+    if label.len() > MAX_ENCODING_LEN {
+        return Err(format!("encoding must be at most {} characters", MAX_ENCODING_LEN));
+    }
+    if !label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')) {
+        return Err(format!("invalid encoding: {:?}", label));
+    }
+    Ok((!label.is_empty()).then(|| label.to_string()))
+}
+
+/// Whether a label names UTF-8
+pub fn is_utf8(label: &str) -> bool {
+    ["utf-8", "utf8", "unicode-1-1-utf-8"].iter().any(|name| label.eq_ignore_ascii_case(name))
+}
+
+/// Watches output for invalid UTF-8
+#[derive(Debug, Default)]
+pub struct Utf8Monitor {
+    /// Incomplete character at the end of the previous batch
+    carry: Vec<u8>,
+    invalid_batches: u32,
+    warned: bool,
+}
+
+impl Utf8Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the next output batch; true exactly once, when the warning is due
+    ///
+    /// Characters split across batches are joined first, so they do not count as invalid.
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        if self.warned {
+            return false;
+        }
+        let joined;
+        let data = if self.carry.is_empty() {
+            data
+        } else {
+            joined = [std::mem::take(&mut self.carry).as_slice(), data].concat();
+            joined.as_slice()
+        };
+        match std::str::from_utf8(data) {
+            Ok(_) => false,
+            Err(e) if e.error_len().is_none() => {
+                self.carry = data[e.valid_up_to()..].to_vec();
+                false
+            }
+            Err(_) => {
+                self.invalid_batches += 1;
+                self.warned = self.invalid_batches >= INVALID_BATCHES_BEFORE_WARNING;
+                self.warned
+            }
+        }
+    }
+
+    /// Batches with invalid UTF-8 seen so far
+    pub fn invalid_batches(&self) -> u32 {
+        self.invalid_batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_encoding() {
+        assert_eq!(validate_encoding("gbk").unwrap(), Some("gbk".to_string()));
+        assert_eq!(validate_encoding("Shift_JIS").unwrap(), Some("Shift_JIS".to_string()));
+        assert_eq!(validate_encoding("").unwrap(), None);
+        assert!(validate_encoding("utf 8").is_err());
+        assert!(validate_encoding(&"x".repeat(MAX_ENCODING_LEN + 1)).is_err());
+        assert!(is_utf8("UTF-8") && is_utf8("utf8") && !is_utf8("gbk"));
+    }
+
+    #[test]
+    fn test_warns_once_after_repeated_invalid_batches() {
+        let mut monitor = Utf8Monitor::new();
+        // "中文" in GBK
+        let gbk = [0xD6, 0xD0, 0xCE, 0xC4];
+        assert!(!monitor.feed(&gbk));
+        assert!(!monitor.feed("ok".as_bytes()));
+        assert!(!monitor.feed(&gbk));
+        assert!(monitor.feed(&gbk));
+        assert!(!monitor.feed(&gbk));
+        assert_eq!(monitor.invalid_batches(), INVALID_BATCHES_BEFORE_WARNING);
+    }
+
+    #[test]
+    fn test_split_characters_are_valid() {
+        let mut monitor = Utf8Monitor::new();
+        let text = "中文😀".repeat(4);
+        for chunk in text.as_bytes().chunks(5) {
+            assert!(!monitor.feed(chunk));
+        }
+        assert_eq!(monitor.invalid_batches(), 0);
+    }
+}
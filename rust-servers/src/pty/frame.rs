@@ -0,0 +1,295 @@
+// Binary PTY frames
+// Shared by output and input:
+// [version: u8 = 1][session_id_length: u16 big-endian][session_id: bytes][data: bytes]
+// Output of sessions started with separate_stderr carries a stream byte before the data:
+// [version: u8 = 1][session_id_length: u16 big-endian][session_id: bytes][stream: u8][data: bytes]
+//
+// Output of sessions started with compress_output may set FLAG_DEFLATE in the version byte;
+// the payload after the session ID (stream byte included) is then raw deflate data. The
+// header itself is never compressed.
+//
+// Migration: version 0 frames had no version byte and a u8 length
+// ([session_id_length: u8][session_id][data]). Their first byte is the id length (36 for
+// a UUID), so they are rejected as an unsupported version instead of being misread.
+
+use thiserror::Error;
+
+/// Binary frame decoding errors
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FrameError {
+    #[error("数据太短")]
+    TooShort,
+    #[error("不支持的帧版本: {0}")]
+    UnsupportedVersion(u8),
+    #[error("session_id 长度不足")]
+    TruncatedSessionId,
+    #[error("session_id 不是有效 UTF-8")]
+    InvalidSessionId,
+}
+
+/// Format version written as the first byte of every frame
+pub const VERSION: u8 = 1;
+
+/// Bytes before the session ID: version and length
+const HEADER_LEN: usize = 3;
+
+/// Set in the version byte of an output frame whose payload is deflate-compressed
+pub const FLAG_DEFLATE: u8 = 0x80;
+
+/// Build a frame for a session
+pub fn encode(session_id: &str, data: &[u8]) -> Vec<u8> {
+    let session_id_bytes = session_id.as_bytes();
+    debug_assert!(session_id_bytes.len() <= u16::MAX as usize);
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + session_id_bytes.len() + data.len());
+    frame.push(VERSION);
+    frame.extend_from_slice(&(session_id_bytes.len() as u16).to_be_bytes());
+    frame.extend_from_slice(session_id_bytes);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Output stream markers of sessions started with separate_stderr
+pub const STREAM_STDOUT: u8 = 0;
+pub const STREAM_STDERR: u8 = 1;
+
+/// Build an output frame tagged with its stream
+pub fn encode_stream(session_id: &str, stream: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = encode(session_id, &[stream]);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Compress the payload of an encoded frame, if that makes it smaller
+///
+/// Uses the fastest deflate level: output is compressed as it streams, so latency matters
+/// more than ratio. Incompressible payloads come back unchanged and without the flag.
+pub fn deflate(frame: Vec<u8>) -> Vec<u8> {
+    use std::io::Write;
+
+    let payload_start = HEADER_LEN + u16::from_be_bytes([frame[1], frame[2]]) as usize;
+    let payload_len = frame.len() - payload_start;
+    let mut header = Vec::with_capacity(payload_start + payload_len / 2);
+    header.push(frame[0] | FLAG_DEFLATE);
+    header.extend_from_slice(&frame[1..payload_start]);
+    let mut encoder = flate2::write::DeflateEncoder::new(header, flate2::Compression::fast());
+    // Writing into a Vec cannot fail
+    let compressed = match encoder.write_all(&frame[payload_start..]).and_then(|_| encoder.finish()) {
+        Ok(compressed) => compressed,
+        Err(_) => return frame,
+    };
+    if compressed.len() < frame.len() {
+        compressed
+    } else {
+        frame
+    }
+}
+
+/// Split a frame into its session ID and payload without copying
+pub fn decode(frame: &[u8]) -> Result<(&str, &[u8]), FrameError> {
+    if frame.len() < HEADER_LEN {
+        return Err(FrameError::TooShort);
+    }
+    if frame[0] != VERSION {
+        return Err(FrameError::UnsupportedVersion(frame[0]));
+    }
+
+    let session_id_end = HEADER_LEN + u16::from_be_bytes([frame[1], frame[2]]) as usize;
+    if frame.len() < session_id_end {
+        return Err(FrameError::TruncatedSessionId);
+    }
+
+    let session_id = std::str::from_utf8(&frame[HEADER_LEN..session_id_end])
+        .map_err(|_| FrameError::InvalidSessionId)?;
+    Ok((session_id, &frame[session_id_end..]))
+}
+
+/// Length of an incomplete UTF-8 sequence at the end of `data` (0 to 3 bytes)
+///
+/// Only data that is valid UTF-8 up to that sequence counts. Output in a legacy
+/// encoding (GBK, Shift-JIS) often ends in a byte that looks like a UTF-8 lead byte;
+/// holding it back would only delay it, so such data is never held back.
+pub fn incomplete_utf8_tail(data: &[u8]) -> usize {
+    match std::str::from_utf8(data) {
+        Ok(_) => 0,
+        // No error length means the input simply ended mid-character
+        Err(e) if e.error_len().is_none() => data.len() - e.valid_up_to(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let frame = encode("abc", b"hello\r");
+        assert_eq!(frame[..3], [VERSION, 0, 3]);
+        assert_eq!(decode(&frame), Ok(("abc", &b"hello\r"[..])));
+    }
+
+    #[test]
+    fn test_round_trip_long_session_id() {
+        let session_id = "n".repeat(300);
+        let frame = encode(&session_id, b"data");
+        assert_eq!(frame[..3], [VERSION, 0x01, 0x2c]);
+        assert_eq!(decode(&frame), Ok((session_id.as_str(), &b"data"[..])));
+    }
+
+    #[test]
+    fn test_stream_frame() {
+        let frame = encode_stream("abc", STREAM_STDERR, b"oops");
+        assert_eq!(decode(&frame), Ok(("abc", &b"\x01oops"[..])));
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let frame = encode("abc", b"");
+        assert_eq!(decode(&frame), Ok(("abc", &b""[..])));
+    }
+
+    #[test]
+    fn test_rejects_malformed_frames() {
+        assert_eq!(decode(&[]), Err(FrameError::TooShort));
+        assert_eq!(decode(&[VERSION, 0]), Err(FrameError::TooShort));
+        assert_eq!(decode(&[VERSION, 0, 5, b'a', b'b']), Err(FrameError::TruncatedSessionId));
+        assert_eq!(decode(&[VERSION, 0, 2, 0xff, 0xfe, b'x']), Err(FrameError::InvalidSessionId));
+    }
+
+    #[test]
+    fn test_rejects_unversioned_frames() {
+        // A version 0 frame for a UUID session starts with its length, 36
+        let mut legacy = vec![36];
+        legacy.extend_from_slice(uuid::Uuid::new_v4().to_string().as_bytes());
+        legacy.extend_from_slice(b"ls\r");
+        assert_eq!(decode(&legacy), Err(FrameError::UnsupportedVersion(36)));
+    }
+
+    fn inflate(payload: &[u8]) -> Vec<u8> {
+        use std::io::Read;
+        let mut data = Vec::new();
+        flate2::read::DeflateDecoder::new(payload).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_deflate_keeps_header_readable() {
+        let output = b"drwxr-xr-x  2 user user 4096 .\r\n".repeat(64);
+        let frame = deflate(encode_stream("abc", STREAM_STDOUT, &output));
+        assert_eq!(frame[..6], [VERSION | FLAG_DEFLATE, 0, 3, b'a', b'b', b'c']);
+        assert!(frame.len() < output.len() / 4);
+        let mut payload = vec![STREAM_STDOUT];
+        payload.extend_from_slice(&output);
+        assert_eq!(inflate(&frame[6..]), payload);
+        // Inputs are never compressed, so decode refuses the flag
+        assert_eq!(decode(&frame), Err(FrameError::UnsupportedVersion(VERSION | FLAG_DEFLATE)));
+    }
+
+    #[test]
+    fn test_deflate_leaves_incompressible_payloads() {
+        let frame = encode("abc", b"ok");
+        assert_eq!(deflate(frame.clone()), frame);
+    }
+
+    #[test]
+    fn test_incomplete_utf8_tail() {
+        let text = "a中😀".as_bytes();
+        assert_eq!(incomplete_utf8_tail(text), 0);
+        // 中 is 3 bytes: cut after 1 and 2 of them
+        assert_eq!(incomplete_utf8_tail(&text[..2]), 1);
+        assert_eq!(incomplete_utf8_tail(&text[..3]), 2);
+        // 😀 is 4 bytes: cut after 3 of them
+        assert_eq!(incomplete_utf8_tail(&text[..7]), 3);
+        assert_eq!(incomplete_utf8_tail(b""), 0);
+        // Invalid bytes are passed through rather than held back
+        assert_eq!(incomplete_utf8_tail(&[b'a', 0xff]), 0);
+        assert_eq!(incomplete_utf8_tail(&[0x80, 0x80, 0x80]), 0);
+    }
+
+    #[test]
+    fn test_legacy_encodings_are_not_held_back() {
+        // "中文" in GBK ends in 0xC4, which would start a 2-byte UTF-8 sequence
+        assert_eq!(incomplete_utf8_tail(&[0xD6, 0xD0, 0xCE, 0xC4]), 0);
+        // "日本" in Shift-JIS
+        assert_eq!(incomplete_utf8_tail(&[0x93, 0xFA, 0x96, 0x7B]), 0);
+    }
+
+    /// Compares binary frame decoding with a JSON `{session_id, data}` message for a large paste
+    ///
+    /// Both paths end with an owned copy of the payload so the numbers are comparable
+    ///
+    /// Run with `cargo test --release frame -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_large_paste_decode() {
+        use std::time::Instant;
+
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let paste: Vec<u8> = (0..4 * 1024 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+        let iterations = 50;
+
+        let frame = encode(&session_id, &paste);
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let (_, data) = decode(std::hint::black_box(&frame)).unwrap();
+            std::hint::black_box(data.to_vec());
+        }
+        let binary = start.elapsed();
+
+        let json = serde_json::json!({
+            "module": "pty",
+            "type": "input",
+            "session_id": session_id,
+            "data": String::from_utf8(paste.clone()).unwrap(),
+        })
+        .to_string();
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let value: serde_json::Value = serde_json::from_str(std::hint::black_box(&json)).unwrap();
+            let data = value["data"].as_str().unwrap().as_bytes().to_vec();
+            std::hint::black_box(data);
+        }
+        let text = start.elapsed();
+
+        let megabytes = (paste.len() * iterations) as f64 / (1024.0 * 1024.0);
+        eprintln!(
+            "binary: {:.0} MiB/s, json: {:.0} MiB/s",
+            megabytes / binary.as_secs_f64(),
+            megabytes / text.as_secs_f64()
+        );
+    }
+
+    /// Deflate throughput and ratio for output frames of typical sizes
+    ///
+    /// Run with `cargo test --release frame -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_output_compression() {
+        use std::time::Instant;
+
+        let session_id = uuid::Uuid::new_v4().to_string();
+        // Build log style lines: mostly repeated words with changing counters
+        let output: Vec<u8> = (0..100_000)
+            .flat_map(|i| format!("\x1b[32m   Compiling\x1b[0m crate-{} v0.{}.{}\r\n", i % 97, i % 13, i).into_bytes())
+            .collect();
+
+        for size in [256, 4 * 1024, 64 * 1024] {
+            let chunks: Vec<&[u8]> = output.chunks(size).take(1024).collect();
+            let (mut before, mut after) = (0, 0);
+            let start = Instant::now();
+            for chunk in &chunks {
+                let frame = encode(&session_id, chunk);
+                before += frame.len();
+                after += std::hint::black_box(deflate(frame)).len();
+            }
+            let elapsed = start.elapsed();
+            eprintln!(
+                "{} byte frames: {:.0} MiB/s, {:.1}% of original size",
+                size,
+                before as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64(),
+                after as f64 * 100.0 / before as f64
+            );
+        }
+    }
+}
@@ -0,0 +1,305 @@
+// Session groups
+// Messages that act on several sessions of a connection at once: resize_all,
+// write_group and destroy_group, optionally limited to one group.
+
+use super::*;
+
+/// Most sessions one write_group message may address
+const MAX_WRITE_GROUP_SESSIONS: usize = 256;
+
+/// Sessions a write_group message addresses
+pub(super) enum WriteTargets {
+    /// Explicit session ids, in the order given
+    Sessions(Vec<String>),
+    /// Every session of the connection in the group
+    Group(String),
+}
+
+impl PtyHandler {
+    /// Handle the resize_all message and resize every session to the same size
+    ///
+    /// With a group, only the sessions in that group are resized.
+    ///
+    /// Applied immediately rather than debounced; a debounced resize still pending for a
+    /// session is cancelled so it cannot override the broadcast size afterwards.
+    pub(super) async fn handle_resize_all(&self, cols: u16, rows: u16, group: Option<&str>) -> Result<Option<ServerResponse>, RouterError> {
+        log_info!("调整全部终端尺寸: {}x{}, group={:?}", cols, rows, group);
+
+        let mut sessions = self.sessions.lock().await;
+        let mut results = Vec::with_capacity(sessions.len());
+        for (session_id, context) in sessions.iter_mut().filter(|(_, context)| context.in_group(group)) {
+            context.cancel_pending_resize();
+            let result = match context.resize_now(TermSize::cells(cols, rows)).await {
+                Ok(()) => serde_json::json!({ "session_id": session_id, "success": true }),
+                Err(e) => {
+                    log_error!("调整终端尺寸失败: session_id={}, {}", session_id, e);
+                    serde_json::json!({ "session_id": session_id, "success": false, "error": e })
+                }
+            };
+            results.push(result);
+        }
+        results.sort_by(|a, b| a["session_id"].as_str().cmp(&b["session_id"].as_str()));
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "resize_all_result",
+            serde_json::json!({
+                "cols": cols,
+                "rows": rows,
+                "group": group,
+                "results": results,
+            }),
+        )))
+    }
+
+    /// Handle the write_group message: write the same text to several sessions
+    ///
+    /// For synchronized input across terminals. The sessions are listed by id, or are
+    /// those in a group. Each session succeeds or fails on its own, with the error
+    /// write_data would return. All writes are queued under one sessions lock, so no
+    /// session is added or removed partway, and awaited once it is released.
+    pub(super) async fn handle_write_group(&self, targets: WriteTargets, text: &str) -> Result<Option<ServerResponse>, RouterError> {
+        let sessions = self.sessions.lock().await;
+        let session_ids = match targets {
+            WriteTargets::Sessions(session_ids) => session_ids,
+            WriteTargets::Group(group) => {
+                let mut session_ids: Vec<String> = sessions
+                    .iter()
+                    .filter(|(_, context)| context.in_group(Some(&group)))
+                    .map(|(session_id, _)| session_id.clone())
+                    .collect();
+                session_ids.sort();
+                session_ids
+            }
+        };
+        if session_ids.len() > MAX_WRITE_GROUP_SESSIONS {
+            return Err(RouterError::InvalidMessage(format!(
+                "write_group accepts at most {} session_ids",
+                MAX_WRITE_GROUP_SESSIONS
+            )));
+        }
+        log_debug!("批量写入: sessions={}, bytes={}", session_ids.len(), text.len());
+
+        let mut seen = HashSet::new();
+        // A repeated id is written once
+        let queued: Vec<_> = session_ids
+            .into_iter()
+            .filter(|id| seen.insert(id.clone()))
+            .map(|session_id| {
+                let queued = self.queue_input(&sessions, &session_id, text.as_bytes());
+                (session_id, queued)
+            })
+            .collect();
+        drop(sessions);
+
+        let mut results = Vec::with_capacity(queued.len());
+        for (session_id, queued) in queued {
+            let written = match queued {
+                Ok(Some(queued)) => queued.finish().await,
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            let result = match written {
+                Ok(()) => serde_json::json!({ "session_id": session_id, "success": true }),
+                Err(e) => {
+                    let mut result = serde_json::json!({ "session_id": session_id, "success": false });
+                    if let RouterError::Coded { code, message, .. } = &e {
+                        result["code"] = serde_json::json!(code);
+                        result["error"] = serde_json::json!(message);
+                    } else {
+                        result["error"] = serde_json::json!(e.to_string());
+                    }
+                    result
+                }
+            };
+            results.push(result);
+        }
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "write_group_result",
+            serde_json::json!({ "results": results }),
+        )))
+    }
+
+    /// Handle the destroy_group message: destroy every session of the connection in a group
+    ///
+    /// Detached sessions have no owner and are left alone. The ids destroyed are returned
+    /// sorted; an unknown group destroys nothing.
+    pub(super) async fn handle_destroy_group(&self, group: &str) -> Result<Option<ServerResponse>, RouterError> {
+        log_info!("销毁会话组: group={}", group);
+        let mut session_ids: Vec<String> = {
+            let sessions = self.sessions.lock().await;
+            sessions
+                .iter()
+                .filter(|(_, context)| context.in_group(Some(group)))
+                .map(|(session_id, _)| session_id.clone())
+                .collect()
+        };
+        session_ids.sort();
+
+        let mut destroyed = Vec::with_capacity(session_ids.len());
+        for session_id in session_ids {
+            // false if the session went away in the meantime
+            if self.handle_destroy(&session_id).await? {
+                destroyed.push(session_id);
+            }
+        }
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "destroy_group_result",
+            serde_json::json!({
+                "group": group,
+                "destroyed": destroyed,
+            }),
+        )))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::pty::tests::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_resize_all_resizes_every_session_and_overrides_pending() {
+        let (handler, _client) = handler_with_client().await;
+        let first = init_session(&handler, serde_json::json!({})).await;
+        let second = init_session(&handler, serde_json::json!({})).await;
+
+        // A debounced resize still waiting must not win over the broadcast
+        handler.handle(&resize_message(&first, 50, 10)).await.unwrap();
+        let response = handler
+            .handle(&message(serde_json::json!({
+                "module": "pty",
+                "type": "resize_all",
+                "cols": 120,
+                "rows": 33,
+            })))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response.msg_type, "resize_all_result");
+        let results = response.payload["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result["success"] == true));
+
+        time::sleep(PtyConfig::default().resize_debounce * 4).await;
+        assert_eq!(session_size(&handler, &first).await, (120, 33));
+        assert_eq!(session_size(&handler, &second).await, (120, 33));
+
+        handler.cleanup_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_group_operations_only_affect_the_group() {
+        let (handler, _client) = handler_with_client().await;
+        let mut left = vec![
+            init_session(&handler, serde_json::json!({ "group": "left" })).await,
+            init_session(&handler, serde_json::json!({ "group": "left" })).await,
+        ];
+        left.sort();
+        let right = init_session(&handler, serde_json::json!({ "group": "right" })).await;
+        let ungrouped = init_session(&handler, serde_json::json!({})).await;
+        let request = |value: serde_json::Value| {
+            let mut value = value;
+            value["module"] = serde_json::json!("pty");
+            message(value)
+        };
+        let session_ids = |response: &ServerResponse| -> Vec<String> {
+            response.payload["sessions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["session_id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let list = handler.handle(&request(serde_json::json!({ "type": "list", "group": "left" }))).await.unwrap().unwrap();
+        assert_eq!(session_ids(&list), left);
+        assert_eq!(list.payload["sessions"][0]["group"], "left");
+
+        let resize = request(serde_json::json!({ "type": "resize_all", "cols": 100, "rows": 30, "group": "right" }));
+        let response = handler.handle(&resize).await.unwrap().unwrap();
+        assert_eq!(response.payload["results"].as_array().unwrap().len(), 1);
+        time::sleep(PtyConfig::default().resize_debounce * 4).await;
+        assert_eq!(session_size(&handler, &right).await, (100, 30));
+        assert_eq!(session_size(&handler, &left[0]).await, (DEFAULT_COLS, DEFAULT_ROWS));
+        assert_eq!(session_size(&handler, &ungrouped).await, (DEFAULT_COLS, DEFAULT_ROWS));
+
+        let write = request(serde_json::json!({ "type": "write_group", "group": "left", "text": "" }));
+        let response = handler.handle(&write).await.unwrap().unwrap();
+        let written: Vec<&str> = response.payload["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["session_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(written, left);
+
+        let destroy = request(serde_json::json!({ "type": "destroy_group", "group": "left" }));
+        let response = handler.handle(&destroy).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "destroy_group_result");
+        assert_eq!(response.payload["destroyed"], serde_json::json!(left));
+        let list = handler.handle(&request(serde_json::json!({ "type": "list" }))).await.unwrap().unwrap();
+        let mut remaining = vec![right.clone(), ungrouped.clone()];
+        remaining.sort();
+        assert_eq!(session_ids(&list), remaining);
+        assert!(handler.is_alive(&right).await && handler.is_alive(&ungrouped).await);
+
+        // Nothing left in the group
+        let response = handler.handle(&destroy).await.unwrap().unwrap();
+        assert_eq!(response.payload["destroyed"], serde_json::json!([]));
+
+        handler.cleanup_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_write_group_writes_to_every_session() {
+        let (handler, mut client) = handler_with_client().await;
+        let mut session_ids = Vec::new();
+        for tag in ["one", "two"] {
+            session_ids.push(init_session(&handler, serde_json::json!({
+                "env": { "TERMY_TEST_VALUE": tag },
+                "shell_args": ["-c", "read line; printf '<%s:%s>' \"$TERMY_TEST_VALUE\" \"$line\"; sleep 5"],
+            }))
+            .await);
+        }
+
+        let request = message(serde_json::json!({
+            "module": "pty",
+            "type": "write_group",
+            "session_ids": [session_ids[0], session_ids[1], "missing", session_ids[0]],
+            "text": "hello\r",
+        }));
+        let response = handler.handle(&request).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "write_group_result");
+        let results = response.payload["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["success"], true);
+        assert_eq!(results[1]["success"], true);
+        assert_eq!(results[2]["session_id"], "missing");
+        assert_eq!(results[2]["success"], false);
+        assert_eq!(results[2]["code"], SESSION_NOT_FOUND);
+
+        let mut output: HashMap<String, Vec<u8>> = HashMap::new();
+        let read = async {
+            while let Some(frame) = client.next().await {
+                if let Message::Binary(data) = frame.unwrap() {
+                    let (session_id, payload) = frame::decode(&data).unwrap();
+                    output.entry(session_id.to_string()).or_default().extend_from_slice(payload);
+                    let done = |id: &String, needle: &[u8]| output.get(id).is_some_and(|out| position(out, needle).is_some());
+                    if done(&session_ids[0], b"<one:hello>") && done(&session_ids[1], b"<two:hello>") {
+                        return;
+                    }
+                }
+            }
+        };
+        time::timeout(Duration::from_secs(5), read).await.expect("timed out waiting for output");
+
+        handler.cleanup_all().await;
+    }
+}
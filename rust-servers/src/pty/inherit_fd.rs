@@ -0,0 +1,257 @@
+// Handing an extra descriptor to the shell (Unix only)
+// portable-pty closes every descriptor above stderr before exec, so the fd cannot just be
+// inherited. The shell is started through the server's inherit-fd helper (see reexec),
+// which collects a duplicate over a Unix socket (SCM_RIGHTS) and installs it under the
+// same number.
+//
+// Lifetime: the server keeps its own descriptor open, it belongs to whoever started the
+// server with it. Every spawn (including a restart) hands over a fresh duplicate, which
+// stays open for as long as the shell and the processes inheriting it keep it; closing it
+// there does not affect the server or other sessions.
+
+use super::reexec::Helper;
+use portable_pty::CommandBuilder;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// `termy-server --inherit-fd <socket> <fd> -- <argv...>`
+pub const HELPER: Helper = Helper { arg: "--inherit-fd", usage: "<socket> <fd>", run: run_helper };
+
+/// How long spawning waits for the helper to collect the descriptor
+#[cfg(unix)]
+const HANDOFF_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Descriptor of the server handed to the shell under the same number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InheritFd {
+    pub fd: i32,
+    /// Binary run as the helper, the server itself
+    pub helper: PathBuf,
+}
+
+/// Check a requested descriptor against the ones the server was told to offer
+///
+/// Only descriptors listed with `--inheritable-fd` may be handed out, so a client cannot
+/// reach the server's own sockets or the PTYs of other sessions.
+#[cfg(unix)]
+pub fn resolve(fd: i32, offered: &[i32]) -> Result<InheritFd, String> {
+    if fd <= 2 {
+        return Err("inherit_fd must be above 2; stdin, stdout and stderr are the terminal".to_string());
+    }
+    if !offered.contains(&fd) {
+        return Err(format!("inherit_fd {} is not offered by the server (see --inheritable-fd)", fd));
+    }
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!("inherit_fd {} is not an open descriptor", fd));
+    }
+    let helper = std::env::current_exe().map_err(|e| format!("cannot locate the server binary: {}", e))?;
+    Ok(InheritFd { fd, helper })
+}
+
+#[cfg(not(unix))]
+pub fn resolve(_fd: i32, _offered: &[i32]) -> Result<InheritFd, String> {
+    Err("inherit_fd is only supported on Unix".to_string())
+}
+
+impl InheritFd {
+    /// Wrap `cmd` so that the helper installs the descriptor before exec'ing it
+    pub fn wrap(&self, socket: &std::path::Path, cmd: &CommandBuilder) -> CommandBuilder {
+        HELPER.wrap(&self.helper, [socket.as_os_str().to_owned(), self.fd.to_string().into()], cmd)
+    }
+}
+
+/// Socket a spawned helper collects the descriptor from
+///
+/// It lives in a fresh directory only the server's user can enter; both are removed on drop.
+#[cfg(unix)]
+pub struct Handoff {
+    listener: std::os::unix::net::UnixListener,
+    dir: PathBuf,
+    fd: i32,
+}
+
+#[cfg(unix)]
+impl Handoff {
+    pub fn listen(fd: i32) -> std::io::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let dir = std::env::temp_dir().join(format!("termy-fd-{}", uuid::Uuid::new_v4()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let listener = match std::os::unix::net::UnixListener::bind(dir.join("socket")) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+        };
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, dir, fd })
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.dir.join("socket")
+    }
+
+    /// Wait for the helper to connect and send it a duplicate of the descriptor
+    ///
+    /// `exited` is polled while waiting, so a helper that died is not waited for.
+    pub fn send(&self, mut exited: impl FnMut() -> bool) -> std::io::Result<()> {
+        let deadline = std::time::Instant::now() + HANDOFF_TIMEOUT;
+        let stream = loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if exited() {
+                        return Err(std::io::Error::other("the shell exited before collecting the descriptor"));
+                    }
+                    if std::time::Instant::now() >= deadline {
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the helper did not collect the descriptor"));
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        send_fd(&stream, self.fd)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Handoff {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Send `fd` over `stream` with one byte of data
+#[cfg(unix)]
+fn send_fd(stream: &std::os::unix::net::UnixStream, fd: i32) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut byte = [0u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<i32>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<i32>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<i32>(), fd);
+    }
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a descriptor sent by [`send_fd`]
+#[cfg(unix)]
+fn recv_fd(stream: &std::os::unix::net::UnixStream) -> std::io::Result<i32> {
+    use std::os::fd::AsRawFd;
+
+    let mut byte = [0u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<i32>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    if unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(std::io::Error::other("no descriptor was received"));
+        }
+        Ok(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<i32>()))
+    }
+}
+
+/// Collect the descriptor, install it under its number and exec the shell; only returns
+/// on failure
+#[cfg(unix)]
+fn run_helper(args: &[OsString], argv: &[OsString]) -> String {
+    let [socket, fd] = args else {
+        return HELPER.usage();
+    };
+    let Some(fd) = fd.to_str().and_then(|s| s.parse::<i32>().ok()).filter(|&fd| fd > 2) else {
+        return "invalid descriptor number".to_string();
+    };
+    let received = match std::os::unix::net::UnixStream::connect(socket).and_then(|stream| recv_fd(&stream)) {
+        Ok(received) => received,
+        Err(e) => return format!("collecting descriptor {} failed: {}", fd, e),
+    };
+    // The socket is closed by now, so its number may be the requested one; dup2 leaves
+    // the copy open across exec
+    if received != fd {
+        if unsafe { libc::dup2(received, fd) } == -1 {
+            return format!("dup2 to {} failed: {}", fd, std::io::Error::last_os_error());
+        }
+        unsafe { libc::close(received) };
+    } else if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+        return format!("clearing close-on-exec of {} failed: {}", fd, std::io::Error::last_os_error());
+    }
+
+    super::reexec::exec(argv)
+}
+
+#[cfg(not(unix))]
+fn run_helper(_args: &[OsString], _argv: &[OsString]) -> String {
+    "only supported on Unix".to_string()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_only_offered_open_descriptors() {
+        assert!(resolve(1, &[1]).is_err());
+        assert!(resolve(1000, &[]).is_err());
+        // Offered but not open
+        assert!(resolve(1000, &[1000]).is_err());
+
+        let (reader, _writer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&reader);
+        assert_eq!(resolve(fd, &[fd]).unwrap().fd, fd);
+    }
+
+    #[test]
+    fn test_descriptor_survives_the_socket() {
+        use std::io::{Read, Write};
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let (mut source, passed) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (server, helper) = std::os::unix::net::UnixStream::pair().unwrap();
+        send_fd(&server, passed.as_raw_fd()).unwrap();
+        let received = recv_fd(&helper).unwrap();
+        assert_ne!(received, passed.as_raw_fd());
+        drop(passed);
+
+        source.write_all(b"out of band").unwrap();
+        let mut received = unsafe { std::os::unix::net::UnixStream::from_raw_fd(received) };
+        let mut buf = [0u8; 11];
+        received.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"out of band");
+    }
+
+    #[test]
+    fn test_wrap_passes_argv_after_separator() {
+        let inherit = InheritFd { fd: 7, helper: PathBuf::from("/srv/termy-server") };
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.arg("-l");
+        let wrapped = inherit.wrap(std::path::Path::new("/tmp/termy-fd-x/socket"), &cmd);
+        let argv: Vec<_> = wrapped.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(argv, ["/srv/termy-server", "--inherit-fd", "/tmp/termy-fd-x/socket", "7", "--", "bash", "-l"]);
+    }
+}
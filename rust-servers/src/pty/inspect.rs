@@ -0,0 +1,213 @@
+// Session inspection
+// Read-only views of a session's output for its owner and watchers: scrollback pages
+// (peek), the shadow screen (screen) and the latest output (tail).
+
+use super::*;
+
+/// Most scrollback bytes one peek message returns; larger buffers are read in pages
+const MAX_PEEK_BYTES: usize = 1024 * 1024;
+
+impl PtyHandler {
+    /// State of a session this connection owns or watches; the inspections are open to both
+    async fn readable_state(&self, session_id: &str) -> Result<Arc<SessionState>, RouterError> {
+        if let Some(context) = self.sessions.lock().await.get(session_id) {
+            return Ok(Arc::clone(&context.state));
+        }
+        if self.is_watching(session_id) {
+            return self.directory.get(session_id).ok_or_else(|| session_not_found(session_id));
+        }
+        Err(self.not_owned(session_id))
+    }
+
+    /// Handle the peek message: return scrollback bytes as data, without replaying them
+    ///
+    /// `offset` is a stream position (bytes of output since the shell started), so pages
+    /// stay stable while new output trims the buffer; it defaults to the oldest buffered
+    /// byte. `next_offset` continues the read, and `end` is where the buffer ends now.
+    /// Watchers may peek too: it reads nothing they do not already receive.
+    pub(super) async fn handle_peek(
+        &self,
+        session_id: &str,
+        offset: Option<u64>,
+        length: Option<usize>,
+    ) -> Result<Option<ServerResponse>, RouterError> {
+        let state = self.readable_state(session_id).await?;
+
+        let length = length.unwrap_or(MAX_PEEK_BYTES).min(MAX_PEEK_BYTES);
+        let (start, end, (offset, data)) = {
+            let scrollback = state
+                .scrollback
+                .lock()
+                .map_err(|_| RouterError::ModuleError("scrollback 不可用".to_string()))?;
+            (scrollback.start(), scrollback.end(), scrollback.range(offset.unwrap_or(0), length))
+        };
+        log_debug!("读取 scrollback: session_id={}, offset={}, {} 字节", session_id, offset, data.len());
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "peek_result",
+            serde_json::json!({
+                "session_id": session_id,
+                "offset": offset,
+                "length": data.len(),
+                "next_offset": offset + data.len() as u64,
+                "start": start,
+                "end": end,
+                "encoding": "base64",
+                "data": encode_base64(&data),
+            }),
+        )))
+    }
+
+    /// Handle the screen message: return what the terminal shows, from the shadow screen
+    ///
+    /// Rows are text without colors or trailing blanks; the cursor is 0-based.
+    pub(super) async fn handle_screen(&self, session_id: &str) -> Result<Option<ServerResponse>, RouterError> {
+        let state = self.readable_state(session_id).await?;
+
+        let screen = state
+            .screen
+            .lock()
+            .map_err(|_| RouterError::ModuleError("屏幕状态不可用".to_string()))?;
+        let screen = screen
+            .as_ref()
+            .ok_or_else(|| RouterError::InvalidMessage("screen requires track_screen at init".to_string()))?;
+        let (row, col) = screen.cursor();
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "screen_result",
+            serde_json::json!({
+                "session_id": session_id,
+                "cols": screen.cols(),
+                "rows": screen.rows(),
+                "lines": screen.lines(),
+                "cursor": { "row": row, "col": col, "visible": screen.cursor_visible() },
+                "alt_screen": screen.alt_screen(),
+            }),
+        )))
+    }
+
+    /// Handle the tail message: return the last [`TAIL_BYTES`] of output
+    ///
+    /// Enough to repaint after a brief disconnect, without replaying or paging through
+    /// the whole scrollback. Open to watchers like peek.
+    pub(super) async fn handle_tail(&self, session_id: &str) -> Result<Option<ServerResponse>, RouterError> {
+        let state = self.readable_state(session_id).await?;
+
+        let data: Vec<u8> = state
+            .tail
+            .lock()
+            .map_err(|_| RouterError::ModuleError("输出尾部缓冲不可用".to_string()))?
+            .iter()
+            .copied()
+            .collect();
+        log_debug!("读取输出尾部: session_id={}, {} 字节", session_id, data.len());
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "tail_result",
+            serde_json::json!({
+                "session_id": session_id,
+                "length": data.len(),
+                "encoding": "base64",
+                "data": encode_base64(&data),
+            }),
+        )))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::pty::tests::*;
+    use futures_util::StreamExt;
+
+    fn screen_message(session_id: &str) -> ModuleMessage {
+        message(serde_json::json!({ "module": "pty", "type": "screen", "session_id": session_id }))
+    }
+
+    #[tokio::test]
+    async fn test_screen_tracks_grid_and_cursor() {
+        let config = PtyConfig {
+            resize_debounce: Duration::ZERO,
+            ..PtyConfig::default()
+        };
+        let (handler, mut client) = handler_with_config(config).await;
+        let session_id = init_session(&handler, serde_json::json!({
+            "shell_args": ["-c", "printf 'junk\\033[2J\\033[Hfirst\\r\\n\\033[31msecond\\033[0m'; sleep 5"],
+            "track_screen": true,
+            "cols": 20,
+            "rows": 4,
+        })).await;
+        read_output_until(&mut client, b"second").await;
+
+        let response = handler.handle(&screen_message(&session_id)).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "screen_result");
+        assert_eq!(response.payload["lines"], serde_json::json!(["first", "second", "", ""]));
+        assert_eq!(response.payload["cursor"], serde_json::json!({ "row": 1, "col": 6, "visible": true }));
+        assert_eq!((response.payload["cols"].as_u64(), response.payload["rows"].as_u64()), (Some(20), Some(4)));
+        assert_eq!(response.payload["alt_screen"], false);
+
+        // The shadow screen follows resizes
+        handler.handle(&resize_message(&session_id, 3, 2)).await.unwrap();
+        let response = handler.handle(&screen_message(&session_id)).await.unwrap().unwrap();
+        assert_eq!(response.payload["lines"], serde_json::json!(["fir", "sec"]));
+        assert_eq!(response.payload["cursor"]["col"], 2);
+
+        // Off unless requested
+        let untracked = init_session(&handler, serde_json::json!({})).await;
+        assert!(matches!(
+            handler.handle(&screen_message(&untracked)).await,
+            Err(RouterError::InvalidMessage(_))
+        ));
+        handler.cleanup_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_tail_returns_latest_output() {
+        let (handler, mut client) = handler_with_client().await;
+        // About 200 KiB of numbered lines, more than the tail holds
+        let session_id = init_session(&handler, serde_json::json!({
+            "shell_args": ["-c", "i=0; while [ $i -lt 20000 ]; do echo line-$i; i=$((i+1)); done; sleep 5"],
+        })).await;
+        let output = read_output_until(&mut client, b"line-19999\r\n").await;
+        assert!(output.len() > TAIL_BYTES);
+
+        let tail = handler.handle(&watch_message("tail", &session_id)).await.unwrap().unwrap();
+        assert_eq!(tail.msg_type, "tail_result");
+        assert_eq!(tail.payload["length"], TAIL_BYTES);
+        assert_eq!(tail.payload["data"], encode_base64(&output[output.len() - TAIL_BYTES..]));
+        handler.cleanup_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_peek_returns_scrollback_pages() {
+        let (handler, mut client) = handler_with_client().await;
+        let session_id = init_session(&handler, serde_json::json!({
+            "shell_args": ["-c", "printf 'alpha-beta-gamma'; sleep 5"],
+        })).await;
+        read_output_until(&mut client, b"gamma").await;
+        let peek = |range: serde_json::Value| {
+            let mut request = serde_json::json!({ "module": "pty", "type": "peek", "session_id": session_id });
+            request.as_object_mut().unwrap().extend(range.as_object().unwrap().clone());
+            let (handler, request) = (&handler, message(request));
+            async move { handler.handle(&request).await }
+        };
+
+        let all = peek(serde_json::json!({})).await.unwrap().unwrap();
+        assert_eq!(all.msg_type, "peek_result");
+        assert_eq!(all.payload["data"], encode_base64(b"alpha-beta-gamma"));
+        assert_eq!((all.payload["start"].as_u64(), all.payload["end"].as_u64()), (Some(0), Some(16)));
+
+        let page = peek(serde_json::json!({ "offset": 6, "length": 4 })).await.unwrap().unwrap();
+        assert_eq!(page.payload["data"], encode_base64(b"beta"));
+        assert_eq!(page.payload["next_offset"], 10);
+        let rest = peek(serde_json::json!({ "offset": 10 })).await.unwrap().unwrap();
+        assert_eq!(rest.payload["data"], encode_base64(b"-gamma"));
+        assert_eq!(rest.payload["next_offset"], rest.payload["end"]);
+
+        // Peeking does not write anything to the terminal
+        while let Ok(Some(frame)) = time::timeout(Duration::from_millis(100), client.next()).await {
+            assert!(!matches!(frame.unwrap(), Message::Binary(_)));
+        }
+        handler.cleanup_all().await;
+    }
+}
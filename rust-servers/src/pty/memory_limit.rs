@@ -0,0 +1,441 @@
+// Capping the memory of a session's processes
+// Linux: the shell runs in a cgroup v2 of its own, created below the server's cgroup (or the
+// one given with --memory-cgroup), with memory.max set and memory.oom.group on, so the kernel
+// kills the whole session when it reaches the cap. The shell is started through the server's
+// join-cgroup helper (see reexec), which moves into the cgroup before it execs the shell.
+// Windows: the shell is put in a job object with a committed-memory limit. portable-pty starts
+// the process running, so it joins right after it is created; a process it starts in that
+// first instant is not capped. Allocations past the limit fail, and the server ends the session.
+
+use super::reexec::Helper;
+#[cfg(target_os = "linux")]
+use portable_pty::CommandBuilder;
+use std::ffi::OsString;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+use std::path::Path;
+
+/// `termy-server --join-cgroup <cgroup dir> -- <argv...>` (Linux only)
+pub const HELPER: Helper = Helper { arg: "--join-cgroup", usage: "<cgroup dir>", run: run_helper };
+
+/// Smallest cap; below it a shell is killed before it shows a prompt
+pub const MIN_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Memory cap requested by init, checked against what the host offers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryLimit {
+    pub bytes: u64,
+    /// cgroup the session cgroups are created in
+    #[cfg(target_os = "linux")]
+    parent: PathBuf,
+    /// Binary run as the helper, the server itself
+    #[cfg(target_os = "linux")]
+    helper: PathBuf,
+}
+
+/// Memory used by a capped session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// In use now; not available on Windows
+    pub current: Option<u64>,
+    /// Most ever in use; needs Linux 5.19 for cgroups
+    pub peak: Option<u64>,
+}
+
+/// cgroup the server runs in, from `/proc/self/cgroup` and the cgroup2 mount point
+#[cfg(target_os = "linux")]
+fn own_cgroup() -> Result<PathBuf, String> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts").map_err(|e| format!("cannot read /proc/self/mounts: {}", e))?;
+    let mount = cgroup2_mount(&mounts).ok_or_else(|| "no cgroup v2 hierarchy is mounted".to_string())?;
+    let membership = std::fs::read_to_string("/proc/self/cgroup").map_err(|e| format!("cannot read /proc/self/cgroup: {}", e))?;
+    let path = unified_cgroup(&membership).ok_or_else(|| "the server is not in a cgroup v2 hierarchy".to_string())?;
+    Ok(mount.join(path.trim_start_matches('/')))
+}
+
+/// Mount point of the cgroup2 file system in a `/proc/self/mounts` listing
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn cgroup2_mount(mounts: &str) -> Option<std::path::PathBuf> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (_, mount_point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+        (fs_type == "cgroup2").then(|| std::path::PathBuf::from(mount_point.replace("\\040", " ")))
+    })
+}
+
+/// Path of the cgroup v2 entry (`0::<path>`) in a `/proc/self/cgroup` listing
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn unified_cgroup(membership: &str) -> Option<&str> {
+    membership.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn lists_controller(list: &str, controller: &str) -> bool {
+    list.split_whitespace().any(|name| name == controller)
+}
+
+/// Check that sessions can be capped at `bytes` and how
+///
+/// On Linux the memory controller must be available below `parent` (the server's own cgroup
+/// by default) and the server must be allowed to create cgroups there and move processes in.
+/// Enabling the controller fails for a cgroup that holds processes itself, as a server
+/// started in a plain systemd service does; give it a delegated cgroup of its own then.
+#[cfg(target_os = "linux")]
+pub fn resolve(bytes: u64, parent: Option<&Path>) -> Result<MemoryLimit, String> {
+    let parent = match parent {
+        Some(parent) => parent.to_path_buf(),
+        None => own_cgroup()?,
+    };
+    let read = |file: &str| {
+        std::fs::read_to_string(parent.join(file))
+            .map_err(|e| format!("{} is not a cgroup v2 directory: {}", parent.display(), e))
+    };
+    if !lists_controller(&read("cgroup.controllers")?, "memory") {
+        return Err(format!("the memory controller is not available in {}", parent.display()));
+    }
+    if !lists_controller(&read("cgroup.subtree_control")?, "memory") {
+        std::fs::write(parent.join("cgroup.subtree_control"), "+memory").map_err(|e| {
+            format!(
+                "cannot enable the memory controller below {}: {}; start the server in a delegated cgroup (see --memory-cgroup)",
+                parent.display(),
+                e
+            )
+        })?;
+    }
+    for path in [parent.clone(), parent.join("cgroup.procs")] {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| "cgroup path contains a NUL byte".to_string())?;
+        if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } != 0 {
+            return Err(format!("the server may not write to {}: {}", path.display(), std::io::Error::last_os_error()));
+        }
+    }
+    let helper = std::env::current_exe().map_err(|e| format!("cannot locate the server binary: {}", e))?;
+    Ok(MemoryLimit { bytes, parent, helper })
+}
+
+#[cfg(windows)]
+pub fn resolve(bytes: u64, _parent: Option<&Path>) -> Result<MemoryLimit, String> {
+    Ok(MemoryLimit { bytes })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn resolve(_bytes: u64, _parent: Option<&Path>) -> Result<MemoryLimit, String> {
+    Err("memory_limit_bytes is only supported on Linux and Windows".to_string())
+}
+
+/// Cap in force for one spawned shell; dropping it kills what is left of the session
+pub struct MemoryCap {
+    bytes: u64,
+    #[cfg(target_os = "linux")]
+    dir: PathBuf,
+    #[cfg(target_os = "linux")]
+    helper: PathBuf,
+    /// OOM kills already reported
+    #[cfg(target_os = "linux")]
+    oom_kills: std::sync::atomic::AtomicU64,
+    #[cfg(windows)]
+    job: windows_sys::Win32::Foundation::HANDLE,
+    /// Receives the job's notifications, the memory limit one among them
+    #[cfg(windows)]
+    port: windows_sys::Win32::Foundation::HANDLE,
+}
+
+// The handles are only used through thread-safe Win32 calls
+#[cfg(windows)]
+unsafe impl Send for MemoryCap {}
+#[cfg(windows)]
+unsafe impl Sync for MemoryCap {}
+
+impl std::fmt::Debug for MemoryCap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryCap").field("bytes", &self.bytes).finish_non_exhaustive()
+    }
+}
+
+impl MemoryLimit {
+    /// Set up the cgroup or job object for one spawn
+    #[cfg(target_os = "linux")]
+    pub fn create(&self) -> Result<MemoryCap, String> {
+        let dir = self.parent.join(format!("termy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).map_err(|e| format!("cannot create cgroup {}: {}", dir.display(), e))?;
+        // Removed again on drop if any of the settings fails
+        let cap = MemoryCap {
+            bytes: self.bytes,
+            dir,
+            helper: self.helper.clone(),
+            oom_kills: std::sync::atomic::AtomicU64::new(0),
+        };
+        let write = |file: &str, value: &str| {
+            std::fs::write(cap.dir.join(file), value).map_err(|e| format!("cannot set {}: {}", file, e))
+        };
+        write("memory.max", &self.bytes.to_string())?;
+        // The whole session goes at the cap, not one process the OOM killer picks
+        write("memory.oom.group", "1")?;
+        // Swap would let the session grow past the cap; absent without swap accounting
+        let _ = write("memory.swap.max", "0");
+        Ok(cap)
+    }
+
+    #[cfg(windows)]
+    pub fn create(&self) -> Result<MemoryCap, String> {
+        use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+        use windows_sys::Win32::System::IO::CreateIoCompletionPort;
+        use windows_sys::Win32::System::JobObjects::{
+            CreateJobObjectW, JobObjectAssociateCompletionPortInformation, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, JOBOBJECT_ASSOCIATE_COMPLETION_PORT, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_JOB_MEMORY, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        };
+
+        let job = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if job.is_null() {
+            return Err(format!("cannot create a job object: {}", std::io::Error::last_os_error()));
+        }
+        let port = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, std::ptr::null_mut(), 0, 1) };
+        if port.is_null() {
+            let error = std::io::Error::last_os_error();
+            unsafe { CloseHandle(job) };
+            return Err(format!("cannot create a completion port: {}", error));
+        }
+        // Closed again on drop if any of the settings fails
+        let cap = MemoryCap { bytes: self.bytes, job, port };
+
+        let association = JOBOBJECT_ASSOCIATE_COMPLETION_PORT { CompletionKey: std::ptr::null_mut(), CompletionPort: port };
+        let mut limits = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_JOB_MEMORY | JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        limits.JobMemoryLimit = usize::try_from(self.bytes).unwrap_or(usize::MAX);
+        let set = unsafe {
+            SetInformationJobObject(
+                job,
+                JobObjectAssociateCompletionPortInformation,
+                (&association as *const JOBOBJECT_ASSOCIATE_COMPLETION_PORT).cast(),
+                std::mem::size_of::<JOBOBJECT_ASSOCIATE_COMPLETION_PORT>() as u32,
+            ) != 0
+                && SetInformationJobObject(
+                    job,
+                    JobObjectExtendedLimitInformation,
+                    (&limits as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION).cast(),
+                    std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+                ) != 0
+        };
+        if !set {
+            return Err(format!("cannot set the job memory limit: {}", std::io::Error::last_os_error()));
+        }
+        Ok(cap)
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    pub fn create(&self) -> Result<MemoryCap, String> {
+        Err("memory_limit_bytes is only supported on Linux and Windows".to_string())
+    }
+}
+
+impl MemoryCap {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Wrap `cmd` so that the helper joins the cgroup before exec'ing it
+    #[cfg(target_os = "linux")]
+    pub fn wrap(&self, cmd: &CommandBuilder) -> CommandBuilder {
+        HELPER.wrap(&self.helper, [&self.dir], cmd)
+    }
+
+    /// Put the spawned shell in the job
+    #[cfg(windows)]
+    pub fn assign(&self, pid: u32) -> Result<(), String> {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::JobObjects::AssignProcessToJobObject;
+        use windows_sys::Win32::System::Threading::{OpenProcess, PROCESS_SET_QUOTA, PROCESS_TERMINATE};
+
+        let process = unsafe { OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, pid) };
+        if process.is_null() {
+            return Err(format!("cannot open the shell process: {}", std::io::Error::last_os_error()));
+        }
+        let assigned = unsafe { AssignProcessToJobObject(self.job, process) } != 0;
+        let error = std::io::Error::last_os_error();
+        unsafe { CloseHandle(process) };
+        if !assigned {
+            return Err(format!("cannot put the shell in the job object: {}", error));
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn usage(&self) -> MemoryUsage {
+        let read = |file: &str| {
+            std::fs::read_to_string(self.dir.join(file)).ok().and_then(|value| value.trim().parse().ok())
+        };
+        MemoryUsage { current: read("memory.current"), peak: read("memory.peak") }
+    }
+
+    #[cfg(windows)]
+    pub fn usage(&self) -> MemoryUsage {
+        use windows_sys::Win32::System::JobObjects::{
+            JobObjectExtendedLimitInformation, QueryInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        };
+
+        let mut limits = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        let queried = unsafe {
+            QueryInformationJobObject(
+                self.job,
+                JobObjectExtendedLimitInformation,
+                (&mut limits as *mut JOBOBJECT_EXTENDED_LIMIT_INFORMATION).cast(),
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+                std::ptr::null_mut(),
+            ) != 0
+        };
+        MemoryUsage { current: None, peak: queried.then_some(limits.PeakJobMemoryUsed as u64) }
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage::default()
+    }
+
+    /// Whether the session reached the cap since the last call
+    #[cfg(target_os = "linux")]
+    pub fn take_exceeded(&self) -> bool {
+        let Ok(events) = std::fs::read_to_string(self.dir.join("memory.events")) else {
+            return false;
+        };
+        let kills = oom_kills(&events);
+        self.oom_kills.fetch_max(kills, std::sync::atomic::Ordering::SeqCst) < kills
+    }
+
+    #[cfg(windows)]
+    pub fn take_exceeded(&self) -> bool {
+        use windows_sys::Win32::System::IO::{GetQueuedCompletionStatus, OVERLAPPED};
+        // JOB_OBJECT_MSG_JOB_MEMORY_LIMIT, from the large SystemServices module
+        const JOB_MEMORY_LIMIT_MESSAGE: u32 = 10;
+
+        let mut exceeded = false;
+        loop {
+            let (mut message, mut key, mut overlapped) = (0u32, 0usize, std::ptr::null_mut::<OVERLAPPED>());
+            if unsafe { GetQueuedCompletionStatus(self.port, &mut message, &mut key, &mut overlapped, 0) } == 0 {
+                return exceeded;
+            }
+            exceeded |= message == JOB_MEMORY_LIMIT_MESSAGE;
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    pub fn take_exceeded(&self) -> bool {
+        false
+    }
+
+    /// Kill every process of the session
+    #[cfg(target_os = "linux")]
+    pub fn kill(&self) {
+        // cgroup.kill needs Linux 5.14; the shell is still killed on its own without it
+        let _ = std::fs::write(self.dir.join("cgroup.kill"), "1");
+    }
+
+    #[cfg(windows)]
+    pub fn kill(&self) {
+        unsafe { windows_sys::Win32::System::JobObjects::TerminateJobObject(self.job, 1) };
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    pub fn kill(&self) {}
+}
+
+/// Processes the OOM killer ended, from a `memory.events` file
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn oom_kills(events: &str) -> u64 {
+    events
+        .lines()
+        .filter_map(|line| line.strip_prefix("oom_kill "))
+        .find_map(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for MemoryCap {
+    fn drop(&mut self) {
+        // A cgroup can only be removed once its processes are gone, which takes a moment
+        // for ones just killed
+        self.kill();
+        if std::fs::remove_dir(&self.dir).is_err() {
+            let dir = self.dir.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    if std::fs::remove_dir(&dir).is_ok() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for MemoryCap {
+    fn drop(&mut self) {
+        // Closing the last job handle kills what is left (JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE)
+        unsafe {
+            windows_sys::Win32::Foundation::CloseHandle(self.job);
+            windows_sys::Win32::Foundation::CloseHandle(self.port);
+        }
+    }
+}
+
+/// Join the cgroup and exec the shell; only returns on failure
+#[cfg(target_os = "linux")]
+fn run_helper(args: &[OsString], argv: &[OsString]) -> String {
+    let [dir] = args else {
+        return HELPER.usage();
+    };
+    // "0" is the writing process itself; its children are started inside the cgroup
+    if let Err(e) = std::fs::write(Path::new(dir).join("cgroup.procs"), "0") {
+        return format!("joining cgroup {} failed: {}", Path::new(dir).display(), e);
+    }
+
+    super::reexec::exec(argv)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_helper(_args: &[OsString], _argv: &[OsString]) -> String {
+    "only supported on Linux".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finds_unified_hierarchy() {
+        let mounts = "proc /proc proc rw 0 0\ncgroup /sys/fs/cgroup/memory cgroup rw,memory 0 0\ncgroup2 /sys/fs/cgroup/unified cgroup2 rw 0 0\n";
+        assert_eq!(cgroup2_mount(mounts), Some(std::path::PathBuf::from("/sys/fs/cgroup/unified")));
+        assert_eq!(cgroup2_mount("proc /proc proc rw 0 0\n"), None);
+
+        let membership = "4:memory:/service\n0::/system.slice/termy.service\n";
+        assert_eq!(unified_cgroup(membership), Some("/system.slice/termy.service"));
+        assert_eq!(unified_cgroup("4:memory:/service\n"), None);
+        assert!(lists_controller("cpuset cpu io memory pids", "memory"));
+        assert!(!lists_controller("cpu memoryx", "memory"));
+    }
+
+    #[test]
+    fn test_counts_oom_kills() {
+        let events = "low 0\nhigh 0\nmax 12\noom 2\noom_kill 3\noom_group_kill 1\n";
+        assert_eq!(oom_kills(events), 3);
+        assert_eq!(oom_kills("low 0\n"), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wrap_passes_argv_after_separator() {
+        let cap = MemoryCap {
+            bytes: MIN_BYTES,
+            dir: PathBuf::from("/sys/fs/cgroup/termy/termy-1"),
+            helper: PathBuf::from("/srv/termy-server"),
+            oom_kills: std::sync::atomic::AtomicU64::new(0),
+        };
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.arg("-l");
+        let argv: Vec<_> = cap.wrap(&cmd).get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(argv, ["/srv/termy-server", "--join-cgroup", "/sys/fs/cgroup/termy/termy-1", "--", "bash", "-l"]);
+    }
+}
@@ -0,0 +1,244 @@
+// DEC private mode tracker
+// Follows DECSET/DECRST sequences (CSI ? Pm h / CSI ? Pm l), including across data chunks
+
+use serde::Serialize;
+
+/// Maximum number of parameters kept for a single sequence
+const MAX_PARAMS: usize = 16;
+
+/// Mouse tracking level requested by the application
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseTracking {
+    #[default]
+    Off,
+    /// 1000: report button press and release
+    Normal,
+    /// 1002: also report motion while a button is held
+    ButtonEvent,
+    /// 1003: report all motion
+    AnyEvent,
+}
+
+/// Input-relevant terminal modes of a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TerminalModes {
+    /// 47 / 1047 / 1049: alternate screen buffer
+    pub alt_screen: bool,
+    /// 1: application cursor keys (DECCKM)
+    pub app_cursor_keys: bool,
+    /// 1000 / 1002 / 1003: mouse reporting
+    pub mouse_tracking: MouseTracking,
+    /// 1006: SGR extended mouse encoding
+    pub mouse_sgr: bool,
+    /// 2004: bracketed paste
+    pub bracketed_paste: bool,
+}
+
+impl TerminalModes {
+    fn apply(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            1 => self.app_cursor_keys = enabled,
+            47 | 1047 | 1049 => self.alt_screen = enabled,
+            1000 | 1002 | 1003 => {
+                self.mouse_tracking = if enabled {
+                    match mode {
+                        1000 => MouseTracking::Normal,
+                        1002 => MouseTracking::ButtonEvent,
+                        _ => MouseTracking::AnyEvent,
+                    }
+                } else {
+                    MouseTracking::Off
+                };
+            }
+            1006 => self.mouse_sgr = enabled,
+            2004 => self.bracketed_paste = enabled,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    CsiEntry,
+    DecPrivate,
+}
+
+/// Incremental DEC private mode parser
+#[derive(Debug)]
+pub struct ModeTracker {
+    state: State,
+    params: Vec<u16>,
+    current: Option<u16>,
+    modes: TerminalModes,
+    /// Alternate screen switches since the last take, in order (true = entered)
+    alt_screen_changes: Vec<bool>,
+}
+
+impl ModeTracker {
+    pub fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: Vec::with_capacity(MAX_PARAMS),
+            current: None,
+            modes: TerminalModes::default(),
+            alt_screen_changes: Vec::new(),
+        }
+    }
+
+    /// Current mode state
+    pub fn modes(&self) -> TerminalModes {
+        self.modes
+    }
+
+    /// Alternate screen switches seen since the last call, oldest first
+    ///
+    /// Every real switch is listed, even when a batch enters and leaves again; setting
+    /// the mode that is already active is not a switch.
+    pub fn take_alt_screen_changes(&mut self) -> Vec<bool> {
+        std::mem::take(&mut self.alt_screen_changes)
+    }
+
+    /// Feed output bytes; returns true when any tracked mode changed
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        let before = self.modes;
+        for &b in data {
+            self.advance(b);
+        }
+        self.modes != before
+    }
+
+    fn advance(&mut self, b: u8) {
+        match self.state {
+            State::Ground => {
+                if b == 0x1b {
+                    self.state = State::Escape;
+                }
+            }
+            State::Escape => {
+                self.state = match b {
+                    b'[' => State::CsiEntry,
+                    0x1b => State::Escape,
+                    _ => State::Ground,
+                };
+            }
+            State::CsiEntry => {
+                if b == b'?' {
+                    self.params.clear();
+                    self.current = None;
+                    self.state = State::DecPrivate;
+                } else {
+                    // Other CSI sequences are not tracked; their remaining bytes are harmless in Ground
+                    self.state = if b == 0x1b { State::Escape } else { State::Ground };
+                }
+            }
+            State::DecPrivate => match b {
+                b'0'..=b'9' => {
+                    let digit = (b - b'0') as u16;
+                    self.current = Some(
+                        self.current
+                            .unwrap_or(0)
+                            .saturating_mul(10)
+                            .saturating_add(digit),
+                    );
+                }
+                b';' => self.push_param(),
+                b'h' | b'l' => {
+                    self.push_param();
+                    let enabled = b == b'h';
+                    let alt_screen = self.modes.alt_screen;
+                    for &mode in &self.params {
+                        self.modes.apply(mode, enabled);
+                    }
+                    if self.modes.alt_screen != alt_screen {
+                        self.alt_screen_changes.push(self.modes.alt_screen);
+                    }
+                    self.state = State::Ground;
+                }
+                0x1b => self.state = State::Escape,
+                _ => self.state = State::Ground,
+            },
+        }
+    }
+
+    fn push_param(&mut self) {
+        if let Some(value) = self.current.take() {
+            if self.params.len() < MAX_PARAMS {
+                self.params.push(value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks_set_and_reset() {
+        let mut tracker = ModeTracker::new();
+        assert!(tracker.feed(b"\x1b[?1049h\x1b[?1h"));
+        assert!(tracker.modes().alt_screen);
+        assert!(tracker.modes().app_cursor_keys);
+
+        assert!(tracker.feed(b"\x1b[?1049l"));
+        assert!(!tracker.modes().alt_screen);
+        assert!(tracker.modes().app_cursor_keys);
+    }
+
+    #[test]
+    fn test_multiple_params_in_one_sequence() {
+        let mut tracker = ModeTracker::new();
+        tracker.feed(b"\x1b[?1002;1006;2004h");
+        let modes = tracker.modes();
+        assert_eq!(modes.mouse_tracking, MouseTracking::ButtonEvent);
+        assert!(modes.mouse_sgr);
+        assert!(modes.bracketed_paste);
+
+        tracker.feed(b"\x1b[?1002l");
+        assert_eq!(tracker.modes().mouse_tracking, MouseTracking::Off);
+        assert!(tracker.modes().mouse_sgr);
+    }
+
+    #[test]
+    fn test_sequence_split_across_chunks() {
+        let mut tracker = ModeTracker::new();
+        assert!(!tracker.feed(b"hello \x1b"));
+        assert!(!tracker.feed(b"[?20"));
+        assert!(tracker.feed(b"04h world"));
+        assert!(tracker.modes().bracketed_paste);
+    }
+
+    #[test]
+    fn test_alt_screen_changes_are_listed_once_each() {
+        let mut tracker = ModeTracker::new();
+        tracker.feed(b"\x1b[?10");
+        tracker.feed(b"49h\x1b[?1049h\x1b[?47h");
+        assert_eq!(tracker.take_alt_screen_changes(), [true]);
+        assert!(tracker.take_alt_screen_changes().is_empty());
+
+        // Leaving and entering again within one chunk are both listed
+        tracker.feed(b"\x1b[?1049lbye\x1b[?1049h\x1b");
+        tracker.feed(b"[?1049l");
+        assert_eq!(tracker.take_alt_screen_changes(), [false, true, false]);
+    }
+
+    #[test]
+    fn test_ignores_non_private_csi() {
+        let mut tracker = ModeTracker::new();
+        // CSI 4 h (insert mode) and SGR are not DEC private modes
+        assert!(!tracker.feed(b"\x1b[4h\x1b[1;31mred\x1b[0m"));
+        assert_eq!(tracker.modes(), TerminalModes::default());
+    }
+
+    #[test]
+    fn test_serializes_modes() {
+        let mut tracker = ModeTracker::new();
+        tracker.feed(b"\x1b[?1003h");
+        let value = serde_json::to_value(tracker.modes()).unwrap();
+        assert_eq!(value["mouse_tracking"], "any_event");
+        assert_eq!(value["alt_screen"], false);
+    }
+}
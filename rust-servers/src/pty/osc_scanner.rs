@@ -1,5 +1,6 @@
 // OSC 133/633 scanner
 // Parses Shell Integration sequences, including across data chunks
+// Also counts bells (BEL outside any OSC, DCS or APC string; the BEL ending a string is not a bell)
 
 #[derive(Debug, Clone, Copy)]
 pub enum OscSource {
@@ -45,121 +46,122 @@ impl OscEvent {
     }
 }
 
+/// Where the scanner is in the escape sequence grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// ESC seen outside a string
+    Escape,
+    /// Inside an OSC string, collected in `buffer`
+    Osc,
+    /// Inside a DCS, APC, PM or SOS string, whose contents are skipped
+    String,
+}
+
 #[derive(Debug)]
 pub struct OscScanner {
+    state: State,
+    /// ESC seen inside a string; ST when `\` follows
+    string_escape: bool,
+    /// OSC contents after `ESC ]`, up to `max_buffer` bytes
     buffer: Vec<u8>,
     max_buffer: usize,
+    /// The OSC outgrew `max_buffer`; it is skipped up to its terminator
+    truncated: bool,
+    bells: usize,
 }
 
 impl OscScanner {
     pub fn new() -> Self {
         Self {
+            state: State::Ground,
+            string_escape: false,
             buffer: Vec::new(),
             max_buffer: 8192,
+            truncated: false,
+            bells: 0,
         }
     }
 
     pub fn scan(&mut self, data: &[u8]) -> Vec<OscEvent> {
-        self.buffer.extend_from_slice(data);
-
         let mut events = Vec::new();
-        let mut index = 0;
-        let len = self.buffer.len();
-
-        while index + 1 < len {
-            if self.buffer[index] == 0x1b && self.buffer[index + 1] == b']' {
-                match self.parse_sequence(index) {
-                    ParseResult::Parsed { next_index, event } => {
-                        if let Some(event) = event {
-                            events.push(event);
-                        }
-                        index = next_index;
-                        continue;
-                    }
-                    ParseResult::Incomplete => break,
-                    ParseResult::Invalid => {
-                        index += 1;
-                        continue;
-                    }
-                }
-            }
-            index += 1;
-        }
-
-        if index > 0 {
-            self.buffer.drain(0..index);
+        for &byte in data {
+            self.advance(byte, &mut events);
         }
-
-        if self.buffer.len() > self.max_buffer {
-            let keep_from = self.buffer.len().saturating_sub(self.max_buffer);
-            self.buffer.drain(0..keep_from);
-        }
-
         events
     }
 
-    fn parse_sequence(&self, start: usize) -> ParseResult {
-        let len = self.buffer.len();
-        if start + 2 >= len {
-            return ParseResult::Incomplete;
-        }
+    /// Number of bells seen since the last call
+    pub fn take_bells(&mut self) -> usize {
+        std::mem::take(&mut self.bells)
+    }
 
-        let mut code_end = None;
-        let mut i = start + 2;
-        while i < len {
-            let b = self.buffer[i];
-            if b == b';' {
-                code_end = Some(i);
-                break;
-            }
-            if !b.is_ascii_digit() {
-                return ParseResult::Invalid;
+    fn advance(&mut self, byte: u8, events: &mut Vec<OscEvent>) {
+        if self.string_escape {
+            self.string_escape = false;
+            if byte == b'\\' {
+                self.finish_string(events);
+                return;
             }
-            i += 1;
+            // An ESC that is not ST cancels the string and starts a new sequence
+            self.end_string();
+            self.state = State::Escape;
         }
 
-        let code_end = match code_end {
-            Some(end) => end,
-            None => return ParseResult::Incomplete,
-        };
-
-        let code = match std::str::from_utf8(&self.buffer[start + 2..code_end]) {
-            Ok(value) => value,
-            Err(_) => return ParseResult::Invalid,
-        };
-
-        let mut terminator_start = None;
-        let mut terminator_len = 0;
-        let mut j = code_end + 1;
-        while j < len {
-            let b = self.buffer[j];
-            if b == 0x07 {
-                terminator_start = Some(j);
-                terminator_len = 1;
-                break;
-            }
-            if b == 0x1b {
-                if j + 1 < len && self.buffer[j + 1] == b'\\' {
-                    terminator_start = Some(j);
-                    terminator_len = 2;
-                    break;
+        match self.state {
+            State::Ground => match byte {
+                0x07 => self.bells += 1,
+                0x1b => self.state = State::Escape,
+                _ => {}
+            },
+            State::Escape => match byte {
+                b']' => self.state = State::Osc,
+                b'P' | b'_' | b'^' | b'X' => self.state = State::String,
+                // Controls run without ending the escape sequence
+                0x07 => self.bells += 1,
+                0x1b => {}
+                _ => self.state = State::Ground,
+            },
+            State::Osc | State::String => match byte {
+                // BEL ends any string (xterm accepts it in place of ST)
+                0x07 => self.finish_string(events),
+                0x1b => self.string_escape = true,
+                // CAN and SUB cancel the string
+                0x18 | 0x1a => self.end_string(),
+                _ if self.state == State::Osc => {
+                    if self.buffer.len() < self.max_buffer {
+                        self.buffer.push(byte);
+                    } else {
+                        self.truncated = true;
+                    }
                 }
-            }
-            j += 1;
+                _ => {}
+            },
         }
+    }
 
-        let terminator_start = match terminator_start {
-            Some(pos) => pos,
-            None => return ParseResult::Incomplete,
-        };
-
-        let payload = &self.buffer[code_end + 1..terminator_start];
-        let event = Self::parse_payload(code, payload);
-
-        ParseResult::Parsed {
-            next_index: terminator_start + terminator_len,
-            event,
+    /// A string ended with its terminator; a complete OSC is parsed
+    fn finish_string(&mut self, events: &mut Vec<OscEvent>) {
+        if self.state == State::Osc && !self.truncated {
+            // The code alone (`ESC ] 112 BEL`) is an OSC without parameters
+            let (code, payload) = match self.buffer.iter().position(|b| *b == b';') {
+                Some(end) => (&self.buffer[..end], &self.buffer[end + 1..]),
+                None => (&self.buffer[..], &[][..]),
+            };
+            if let Some(event) = std::str::from_utf8(code)
+                .ok()
+                .and_then(|code| Self::parse_payload(code, payload))
+            {
+                events.push(event);
+            }
         }
+        self.end_string();
+    }
+
+    fn end_string(&mut self) {
+        self.buffer.clear();
+        self.truncated = false;
+        self.state = State::Ground;
     }
 
     fn parse_payload(code: &str, payload: &[u8]) -> Option<OscEvent> {
@@ -192,8 +194,69 @@ impl OscScanner {
     }
 }
 
-enum ParseResult {
-    Parsed { next_index: usize, event: Option<OscEvent> },
-    Incomplete,
-    Invalid,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bells(chunks: &[&[u8]]) -> usize {
+        let mut scanner = OscScanner::new();
+        for chunk in chunks {
+            scanner.scan(chunk);
+        }
+        scanner.take_bells()
+    }
+
+    #[test]
+    fn test_parses_events_split_across_chunks() {
+        let mut scanner = OscScanner::new();
+        assert!(scanner.scan(b"$ \x1b]13").is_empty());
+        assert!(scanner.scan(b"3;D;2\x1b").is_empty());
+        let events = scanner.scan(b"\\\x1b]633;A\x07");
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].event_name(), events[0].exit_code()), ("command_end", Some(2)));
+        assert_eq!((events[1].event_name(), events[1].source_name()), ("prompt_start", "osc633"));
+    }
+
+    #[test]
+    fn test_counts_bells_outside_strings_only() {
+        assert_eq!(bells(&[b"ding\x07", b"\x1b]0;title\x07", b"\x07"]), 2);
+        // Terminator split from its OSC
+        assert_eq!(bells(&[b"\x1b]0;tit", b"le\x07"]), 0);
+    }
+
+    #[test]
+    fn test_parameterless_osc_terminator_is_not_a_bell() {
+        assert_eq!(bells(&[b"\x1b]112\x07\x1b]104\x07"]), 0);
+    }
+
+    #[test]
+    fn test_oversized_osc_terminator_is_not_a_bell() {
+        let mut clipboard = b"\x1b]52;c;".to_vec();
+        clipboard.extend(std::iter::repeat_n(b'A', 12 * 1024));
+        clipboard.push(0x07);
+        let mut scanner = OscScanner::new();
+        for chunk in clipboard.chunks(4096) {
+            assert!(scanner.scan(chunk).is_empty());
+        }
+        assert_eq!(scanner.take_bells(), 0);
+        // Back in ground state afterwards
+        assert_eq!(scanner.scan(b"\x1b]133;A\x07").len(), 1);
+        assert_eq!(bells(&[&clipboard, b"\x07"]), 1);
+    }
+
+    #[test]
+    fn test_dcs_and_apc_terminators_are_not_bells() {
+        assert_eq!(bells(&[b"\x1bPq#0;2;0;0;0\x07", b"\x1b_Gf=100\x07"]), 0);
+        // ST-terminated strings leave a following bell alone
+        assert_eq!(bells(&[b"\x1bP1$r0m\x1b\\\x07"]), 1);
+    }
+
+    #[test]
+    fn test_esc_inside_a_string_starts_a_new_sequence() {
+        let mut scanner = OscScanner::new();
+        let events = scanner.scan(b"\x1b]0;unterminated\x1b]133;B\x07");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_name(), "command_start");
+        assert_eq!(scanner.take_bells(), 0);
+    }
 }
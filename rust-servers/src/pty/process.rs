@@ -0,0 +1,139 @@
+// Foreground process of a PTY session
+// Unix: the foreground process group of the terminal (tcgetpgrp), named from /proc on
+// Linux and proc_pidpath on macOS.
+// Windows: ConPTY has no process groups, so the newest descendant of the shell is taken.
+// Also the environment snapshot of a process, read from /proc where it exists.
+
+/// Process currently running in the foreground of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForegroundProcess {
+    pub pid: u32,
+    /// Executable name without directories (and without `.exe` on Windows)
+    pub name: Option<String>,
+}
+
+/// Foreground process of the terminal on `master_fd`
+///
+/// The process group id is the pid of its leader, which is the job the shell started
+/// (or the shell itself at the prompt).
+#[cfg(unix)]
+pub fn foreground(master_fd: std::os::fd::RawFd) -> Option<ForegroundProcess> {
+    let pgid = unsafe { libc::tcgetpgrp(master_fd) };
+    if pgid <= 0 {
+        return None;
+    }
+    let pid = pgid as u32;
+    Some(ForegroundProcess { pid, name: process_name(pid) })
+}
+
+#[cfg(target_os = "linux")]
+fn process_name(pid: u32) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end_matches('\n').to_string()).filter(|name| !name.is_empty())
+}
+
+#[cfg(target_os = "macos")]
+fn process_name(pid: u32) -> Option<String> {
+    let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe { libc::proc_pidpath(pid as libc::c_int, buf.as_mut_ptr().cast(), buf.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    let path = std::path::PathBuf::from(String::from_utf8_lossy(&buf).into_owned());
+    path.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn process_name(_pid: u32) -> Option<String> {
+    None
+}
+
+/// Newest process in the tree below `shell_pid`, or the shell when it has no children
+///
+/// Snapshot order follows creation closely enough to pick the command the user started
+/// last; console hosts are skipped.
+#[cfg(windows)]
+pub fn foreground(shell_pid: u32) -> Option<ForegroundProcess> {
+    let processes = snapshot()?;
+    let name_of = |pid: u32| processes.iter().find(|p| p.0 == pid).map(|p| p.2.clone());
+    let mut current = shell_pid;
+    let mut visited = vec![current];
+    while let Some(child) = processes
+        .iter()
+        .filter(|(pid, parent, name)| *parent == current && !visited.contains(pid) && !name.eq_ignore_ascii_case("conhost"))
+        .map(|(pid, _, _)| *pid)
+        .next_back()
+    {
+        visited.push(child);
+        current = child;
+    }
+    Some(ForegroundProcess { pid: current, name: name_of(current) })
+}
+
+/// All processes as (pid, parent pid, name without `.exe`)
+#[cfg(windows)]
+fn snapshot() -> Option<Vec<(u32, u32, String)>> {
+    use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+    };
+
+    let handle = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if handle == INVALID_HANDLE_VALUE {
+        return None;
+    }
+    let mut entry: PROCESSENTRY32W = unsafe { std::mem::zeroed() };
+    entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+    let mut processes = Vec::new();
+    let mut more = unsafe { Process32FirstW(handle, &mut entry) } != 0;
+    while more {
+        let len = entry.szExeFile.iter().position(|&c| c == 0).unwrap_or(entry.szExeFile.len());
+        let exe = String::from_utf16_lossy(&entry.szExeFile[..len]);
+        let stem = exe.len().saturating_sub(4);
+        let name = match exe.get(stem..) {
+            Some(ext) if ext.eq_ignore_ascii_case(".exe") => exe[..stem].to_string(),
+            _ => exe,
+        };
+        processes.push((entry.th32ProcessID, entry.th32ParentProcessID, name));
+        more = unsafe { Process32NextW(handle, &mut entry) } != 0;
+    }
+    unsafe { CloseHandle(handle) };
+    Some(processes)
+}
+
+/// Environment a process was started with, from `/proc/<pid>/environ`
+///
+/// This is the block passed to exec: variables the shell exports later (from its rc files
+/// or at the prompt) are not in it. Writing `env` into the terminal would see those, but it
+/// echoes into the user's screen and races with their typing, so it is not done.
+/// Entries without `=` are skipped; values that are not UTF-8 are converted lossily.
+#[cfg(unix)]
+pub fn environment(pid: u32) -> Result<std::collections::BTreeMap<String, String>, String> {
+    let path = format!("/proc/{}/environ", pid);
+    let block = std::fs::read(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound if !std::path::Path::new("/proc/self").exists() => {
+            "environment snapshot needs /proc, which this system does not have".to_string()
+        }
+        _ => format!("cannot read {}: {}", path, e),
+    })?;
+    Ok(parse_environ(&block))
+}
+
+#[cfg(windows)]
+pub fn environment(_pid: u32) -> Result<std::collections::BTreeMap<String, String>, String> {
+    Err("environment snapshot is not supported on Windows".to_string())
+}
+
+/// Parse a NUL-separated `NAME=value` block
+#[cfg_attr(windows, allow(dead_code))]
+fn parse_environ(block: &[u8]) -> std::collections::BTreeMap<String, String> {
+    block
+        .split(|&b| b == 0)
+        .filter_map(|entry| {
+            let eq = entry.iter().position(|&b| b == b'=')?;
+            let name = String::from_utf8_lossy(&entry[..eq]).into_owned();
+            (!name.is_empty()).then(|| (name, String::from_utf8_lossy(&entry[eq + 1..]).into_owned()))
+        })
+        .collect()
+}
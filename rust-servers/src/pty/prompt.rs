@@ -0,0 +1,149 @@
+// Prompt detection
+// A fallback to shell integration (OSC 133) for telling when a command finished: the start
+// of each output line is matched against a client-supplied pattern for the shell's prompt.
+
+use regex_automata::meta::{self, Regex};
+use regex_automata::{Anchored, Input};
+use super::clean_text::EscapeFilter;
+
+/// Longest accepted pattern
+const MAX_PATTERN_LEN: usize = 256;
+
+/// Bytes at the start of a line the pattern is matched against; the rest of a line is ignored
+pub const MATCH_WINDOW: usize = 256;
+
+/// Memory the compiled pattern may use, so a pathological one is rejected at init
+const COMPILED_SIZE_LIMIT: usize = 1 << 20;
+
+/// Compile a prompt pattern; it always matches at the start of a line, `^` is implied
+pub fn compile(pattern: &str) -> Result<Regex, String> {
+    if pattern.is_empty() {
+        return Err("prompt_regex must not be empty".to_string());
+    }
+    if pattern.len() > MAX_PATTERN_LEN {
+        return Err(format!("prompt_regex must be at most {} characters", MAX_PATTERN_LEN));
+    }
+    let config = meta::Config::new()
+        .nfa_size_limit(Some(COMPILED_SIZE_LIMIT))
+        .hybrid_cache_capacity(COMPILED_SIZE_LIMIT);
+    let regex = Regex::builder()
+        .configure(config)
+        .build(pattern)
+        .map_err(|e| format!("invalid prompt_regex: {}", e))?;
+    if regex.is_match("") {
+        return Err("prompt_regex must not match an empty line".to_string());
+    }
+    Ok(regex)
+}
+
+/// Matches output lines against a prompt pattern
+///
+/// Escape sequences are skipped, so a colored prompt matches its plain text. Output is
+/// taken in batches of any size; a line split across batches is matched as it grows.
+/// Each line reports at most one prompt, so a prompt redrawn after `\r` is not repeated.
+pub struct PromptDetector {
+    regex: Regex,
+    /// Start of the current line, escape sequences removed
+    line: Vec<u8>,
+    escape: EscapeFilter,
+    /// The current line already matched
+    matched: bool,
+    /// The line grew since it was last matched against
+    dirty: bool,
+}
+
+impl PromptDetector {
+    pub fn new(regex: Regex) -> Self {
+        Self { regex, line: Vec::new(), escape: EscapeFilter::new(), matched: false, dirty: false }
+    }
+
+    /// Feed an output batch; returns the prompts found, as matched text
+    pub fn feed(&mut self, data: &[u8]) -> Vec<String> {
+        let mut prompts = Vec::new();
+        for &byte in data {
+            if !self.escape.is_text(byte) {
+                continue;
+            }
+            match byte {
+                b'\n' => {
+                    prompts.extend(self.check());
+                    self.line.clear();
+                    self.matched = false;
+                }
+                b'\r' => {
+                    prompts.extend(self.check());
+                    self.line.clear();
+                }
+                0x20.. if self.line.len() < MATCH_WINDOW => {
+                    self.line.push(byte);
+                    self.dirty = true;
+                }
+                _ => {}
+            }
+        }
+        prompts.extend(self.check());
+        prompts
+    }
+
+    /// Match the current line, unless it already matched or is unchanged
+    fn check(&mut self) -> Option<String> {
+        if self.matched || !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        let found = self.regex.search(&Input::new(&self.line).anchored(Anchored::Yes))?;
+        self.matched = true;
+        Some(String::from_utf8_lossy(&self.line[found.range()]).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(pattern: &str) -> PromptDetector {
+        PromptDetector::new(compile(pattern).unwrap())
+    }
+
+    #[test]
+    fn test_compile_rejects_bad_patterns() {
+        assert!(compile("[a-z").is_err());
+        assert!(compile("").is_err());
+        assert!(compile("x*").is_err());
+        assert!(compile(&"a".repeat(MAX_PATTERN_LEN + 1)).is_err());
+        assert!(compile(r"\w+@\w+ \$ ").is_ok());
+    }
+
+    #[test]
+    fn test_matches_only_at_line_start() {
+        let mut prompts = detector(r"\$ ");
+        assert_eq!(prompts.feed(b"cost: $ 5\r\n"), Vec::<String>::new());
+        assert_eq!(prompts.feed(b"done\r\n$ "), vec!["$ "]);
+    }
+
+    #[test]
+    fn test_prompt_split_across_batches_and_colored() {
+        let mut prompts = detector(r"build> ");
+        assert!(prompts.feed(b"output\r\n\x1b[32mbui").is_empty());
+        assert!(prompts.feed(b"ld\x1b[0m").is_empty());
+        assert_eq!(prompts.feed(b"> "), vec!["build> "]);
+        assert!(prompts.feed(b"ls").is_empty());
+        assert!(prompts.feed(b"\x1b]0;title\x07\r\n").is_empty());
+        assert_eq!(prompts.feed(b"a\r\nbuild> "), vec!["build> "]);
+    }
+
+    #[test]
+    fn test_redrawn_prompt_is_reported_once() {
+        let mut prompts = detector(r"\$ ");
+        assert_eq!(prompts.feed(b"$ "), vec!["$ "]);
+        assert!(prompts.feed(b"\r$ ls").is_empty());
+        assert_eq!(prompts.feed(b"\r\nfile\r\n$ "), vec!["$ "]);
+    }
+
+    #[test]
+    fn test_only_the_match_window_is_matched() {
+        let mut prompts = detector(r"x+\$ ");
+        let long = [vec![b'x'; MATCH_WINDOW], b"$ ".to_vec()].concat();
+        assert!(prompts.feed(&long).is_empty());
+    }
+}
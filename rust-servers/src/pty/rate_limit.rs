@@ -0,0 +1,125 @@
+// Output rate limiting
+// Token bucket that paces how fast a session's output is read, and the throttle that
+// keeps a repeating log message from flooding stderr
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Shortest interval between two logs from the same call site
+pub const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token bucket measured in bytes
+///
+/// Output is never dropped: a batch larger than the available tokens is let through
+/// and the deficit is paid back by waiting before the next read.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket allowing `bytes_per_sec`, with a quarter second of burst
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        let capacity = (bytes_per_sec / 4.0).max(1.0);
+        Self {
+            bytes_per_sec,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` and return how long to wait before reading more
+    pub fn take(&mut self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+/// Rate limit for the messages of one log call site
+///
+/// The first message always goes through; others within [`LOG_INTERVAL`] of the last
+/// logged one are counted, and the count is reported with the next message that goes out.
+#[derive(Debug)]
+pub struct LogThrottle {
+    state: Mutex<(Option<Instant>, u64)>,
+}
+
+impl LogThrottle {
+    pub const fn new() -> Self {
+        Self { state: Mutex::new((None, 0)) }
+    }
+
+    /// Whether to log now; `Some` carries the number of messages suppressed before it
+    pub fn admit(&self) -> Option<u64> {
+        self.admit_at(Instant::now())
+    }
+
+    fn admit_at(&self, now: Instant) -> Option<u64> {
+        // A poisoned lock must not silence error logging
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, suppressed) = &mut *state;
+        if last.is_some_and(|last| now.saturating_duration_since(last) < LOG_INTERVAL) {
+            *suppressed += 1;
+            return None;
+        }
+        *last = Some(now);
+        Some(std::mem::take(suppressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_passes_then_deficit_waits() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.last_refill;
+        assert_eq!(bucket.take_at(250, start), Duration::ZERO);
+        // 500 bytes over the burst take half a second to pay back
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_refill_is_capped_at_capacity() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.last_refill;
+        bucket.take_at(250, start);
+        // A long idle period only refills the quarter-second burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(250, later), Duration::ZERO);
+        assert_eq!(bucket.take_at(100, later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_log_throttle_reports_suppressed_messages() {
+        let throttle = LogThrottle::new();
+        let start = Instant::now();
+        // The first message is never held back
+        assert_eq!(throttle.admit_at(start), Some(0));
+        assert_eq!(throttle.admit_at(start + Duration::from_millis(10)), None);
+        assert_eq!(throttle.admit_at(start + Duration::from_millis(900)), None);
+        // The next one after the interval carries the count of those suppressed
+        let next = start + LOG_INTERVAL;
+        assert_eq!(throttle.admit_at(next), Some(2));
+        assert_eq!(throttle.admit_at(next + Duration::from_millis(500)), None);
+        assert_eq!(throttle.admit_at(next + LOG_INTERVAL * 5), Some(1));
+        assert_eq!(throttle.admit_at(next + LOG_INTERVAL * 10), Some(0));
+    }
+}
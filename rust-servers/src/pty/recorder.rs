@@ -0,0 +1,212 @@
+// asciinema v2 recorder
+// Writes session output to a .cast file: a JSON header line, then one [time, code, data] event per line
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Resolve a `record_path` inside the directory the server allows recordings in
+///
+/// The path comes from the client, so without a directory (see `PtyConfig::record_dir`)
+/// recording is refused: the server must not write to an arbitrary file of its user. A
+/// relative path is taken inside the directory; symlinks are resolved before the check.
+pub fn resolve_path(path: &str, record_dir: Option<&Path>) -> Result<PathBuf, String> {
+    let Some(record_dir) = record_dir else {
+        return Err("record_path is not enabled on this server".to_string());
+    };
+    let outside = || format!("record_path must be a file under {}", record_dir.display());
+    let dir = record_dir
+        .canonicalize()
+        .map_err(|e| format!("record directory {} is unusable: {}", record_dir.display(), e))?;
+    let requested = Path::new(path);
+    if requested.components().any(|component| component == Component::ParentDir) {
+        return Err(outside());
+    }
+    // An absolute path replaces the directory and has to name a place inside it anyway
+    let joined = record_dir.join(requested);
+    let (Some(parent), Some(name)) = (joined.parent(), joined.file_name()) else {
+        return Err(outside());
+    };
+    let parent = parent.canonicalize().map_err(|e| format!("record_path {}: {}", path, e))?;
+    if !parent.starts_with(&dir) {
+        return Err(outside());
+    }
+    Ok(parent.join(name))
+}
+
+/// Recorder for one session
+pub struct CastRecorder {
+    writer: BufWriter<File>,
+    start: Instant,
+    /// Trailing bytes of an incomplete UTF-8 sequence, completed by the next chunk
+    pending: Vec<u8>,
+}
+
+impl CastRecorder {
+    /// Create the cast file and write its header; an existing file is never overwritten
+    pub fn create(path: &Path, cols: u16, rows: u16, term: &str) -> io::Result<Self> {
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(path)?);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let header = serde_json::json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+            "env": { "TERM": term },
+        });
+        writeln!(writer, "{}", header)?;
+        // Surface an unwritable target now rather than on the first output
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Append an output chunk
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        let text = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.to_string(),
+            // An incomplete sequence at the end waits for the next chunk
+            Err(e) if e.error_len().is_none() => {
+                let valid = e.valid_up_to();
+                let text = String::from_utf8_lossy(&self.pending[..valid]).into_owned();
+                self.pending.drain(..valid);
+                return self.event("o", &text);
+            }
+            Err(_) => String::from_utf8_lossy(&self.pending).into_owned(),
+        };
+        self.pending.clear();
+        self.event("o", &text)
+    }
+
+    /// Record a terminal resize
+    pub fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        self.event("r", &format!("{}x{}", cols, rows))
+    }
+
+    /// Flush buffered events to disk
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let text = String::from_utf8_lossy(&self.pending).into_owned();
+            self.pending.clear();
+            self.event("o", &text)?;
+        }
+        self.writer.flush()
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let line = serde_json::json!([(elapsed * 1_000_000.0).round() / 1_000_000.0, code, data]);
+        writeln!(self.writer, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("termy-{}-{}.cast", name, uuid::Uuid::new_v4()))
+    }
+
+    fn read_lines(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_writes_header_and_events() {
+        let path = temp_path("events");
+        let mut recorder = CastRecorder::create(&path, 100, 30, "xterm-256color").unwrap();
+        recorder.output(b"hello").unwrap();
+        recorder.resize(120, 40).unwrap();
+        recorder.finish().unwrap();
+
+        let lines = read_lines(&path);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 100);
+        assert_eq!(lines[0]["height"], 30);
+        assert_eq!(lines[0]["env"]["TERM"], "xterm-256color");
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "hello");
+        assert_eq!(lines[2][1], "r");
+        assert_eq!(lines[2][2], "120x40");
+        assert!(lines[2][0].as_f64().unwrap() >= lines[1][0].as_f64().unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_keeps_utf8_split_across_chunks() {
+        let path = temp_path("utf8");
+        let mut recorder = CastRecorder::create(&path, 80, 24, "xterm").unwrap();
+        let bytes = "终端".as_bytes();
+        recorder.output(&bytes[..2]).unwrap();
+        recorder.output(&bytes[2..]).unwrap();
+        recorder.finish().unwrap();
+
+        let lines = read_lines(&path);
+        let output: String = lines[1..].iter().map(|event| event[2].as_str().unwrap()).collect();
+        assert_eq!(output, "终端");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_create_fails_for_unwritable_path() {
+        let path = std::env::temp_dir().join("termy-missing-dir").join(uuid::Uuid::new_v4().to_string()).join("x.cast");
+        assert!(CastRecorder::create(&path, 80, 24, "xterm").is_err());
+    }
+
+    #[test]
+    fn test_create_keeps_existing_files() {
+        let path = temp_path("existing");
+        std::fs::write(&path, "keep").unwrap();
+        let result = CastRecorder::create(&path, 80, 24, "xterm");
+        assert!(matches!(result, Err(e) if e.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_path_stays_inside_the_record_dir() {
+        let dir = std::env::temp_dir().join(format!("termy-records-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        let canonical = dir.canonicalize().unwrap();
+
+        assert!(resolve_path("one.cast", None).is_err());
+        assert_eq!(resolve_path("one.cast", Some(&dir)).unwrap(), canonical.join("one.cast"));
+        assert_eq!(resolve_path("nested/two.cast", Some(&dir)).unwrap(), canonical.join("nested").join("two.cast"));
+        let absolute = dir.join("three.cast");
+        assert_eq!(resolve_path(absolute.to_str().unwrap(), Some(&dir)).unwrap(), canonical.join("three.cast"));
+
+        assert!(resolve_path("../one.cast", Some(&dir)).is_err());
+        assert!(resolve_path("nested/../../one.cast", Some(&dir)).is_err());
+        let elsewhere = std::env::temp_dir().join("elsewhere.cast");
+        assert!(resolve_path(elsewhere.to_str().unwrap(), Some(&dir)).is_err());
+        assert!(resolve_path("missing/one.cast", Some(&dir)).is_err());
+        assert!(resolve_path("one.cast", Some(&dir.join("missing"))).is_err());
+
+        // A symlink out of the directory is resolved before the check
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("out")).unwrap();
+            assert!(resolve_path("out/one.cast", Some(&dir)).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+// Starting a shell through the server binary itself
+// portable-pty has no hook between fork and exec, so what has to happen in the shell's own
+// process (switching user, installing an extra descriptor, joining a cgroup) is done by the
+// server re-executed in a helper mode, which then execs the shell:
+//   termy-server <helper arg> <helper args...> -- <argv...>
+// A helper that fails writes the reason to stderr, which is the terminal, and exits with
+// 126, the status a shell reports for a command it found but could not run.
+
+use portable_pty::CommandBuilder;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+/// A mode the server binary can be re-executed in
+pub struct Helper {
+    /// First argument, which selects the mode
+    pub arg: &'static str,
+    /// The helper's own arguments, as shown in its usage message
+    pub usage: &'static str,
+    /// Prepare the process from the helper's arguments and exec the argv after `--`; only
+    /// returns on failure, with the reason
+    pub run: fn(&[OsString], &[OsString]) -> String,
+}
+
+impl Helper {
+    /// Command that runs `binary` as this helper with `args`, then `cmd`
+    ///
+    /// Only the argv moves over; cwd and environment are set on the returned command.
+    pub fn wrap<I>(&self, binary: &Path, args: I, cmd: &CommandBuilder) -> CommandBuilder
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        let mut wrapper = CommandBuilder::new(binary);
+        wrapper.arg(self.arg);
+        for arg in args {
+            wrapper.arg(arg);
+        }
+        wrapper.arg("--");
+        for arg in cmd.get_argv() {
+            wrapper.arg(arg);
+        }
+        wrapper
+    }
+
+    /// Message for arguments that do not fit the helper
+    pub fn usage(&self) -> String {
+        format!("usage: {} -- <program> [args...]", self.usage)
+    }
+}
+
+/// Run as one of `helpers` when the process was started as it; otherwise return
+///
+/// Must be called before anything else in `main`: a helper never returns.
+pub fn run_if_requested(helpers: &[Helper]) {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let Some(helper) = args.get(1).and_then(|arg| helpers.iter().find(|helper| arg == helper.arg)) else {
+        return;
+    };
+    let args = &args[2..];
+    let error = match args.iter().position(|arg| arg == "--") {
+        Some(sep) if sep + 1 < args.len() => (helper.run)(&args[..sep], &args[sep + 1..]),
+        _ => helper.usage(),
+    };
+    eprintln!("termy-server {}: {}", helper.arg, error);
+    std::process::exit(126);
+}
+
+/// Replace the process with `argv`, looked up in PATH; only returns on failure
+#[cfg(unix)]
+pub fn exec(argv: &[OsString]) -> String {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let argv: Vec<CString> = match argv.iter().map(|arg| CString::new(arg.as_bytes())).collect() {
+        Ok(argv) => argv,
+        Err(_) => return "argument contains a NUL byte".to_string(),
+    };
+    let mut ptrs: Vec<*const libc::c_char> = argv.iter().map(|arg| arg.as_ptr()).collect();
+    ptrs.push(std::ptr::null());
+    unsafe { libc::execvp(ptrs[0], ptrs.as_ptr()) };
+    format!("exec {} failed: {}", argv[0].to_string_lossy(), std::io::Error::last_os_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_passes_argv_after_separator() {
+        let helper = Helper { arg: "--test-helper", usage: "<value>", run: |_, _| String::new() };
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.arg("-l");
+        let wrapped = helper.wrap(Path::new("/srv/termy-server"), ["a b", ""], &cmd);
+        let argv: Vec<_> = wrapped.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(argv, ["/srv/termy-server", "--test-helper", "a b", "", "--", "bash", "-l"]);
+        assert_eq!(helper.usage(), "usage: <value> -- <program> [args...]");
+    }
+}
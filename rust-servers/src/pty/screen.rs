@@ -0,0 +1,651 @@
+// Shadow screen
+// A text-only model of what the terminal shows, fed by the same output as the client: the
+// grid of characters and the cursor, for server-side search and selection. Colors and other
+// attributes are not kept.
+
+/// Second cell of a double-width character
+const WIDE_TAIL: char = '\0';
+
+/// Tab stops are every this many columns
+const TAB_WIDTH: usize = 8;
+
+/// Maximum number of parameters kept for a single sequence
+const MAX_PARAMS: usize = 16;
+
+/// Where the parser is within the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// After ESC
+    Escape,
+    /// After ESC and an intermediate byte, as in `ESC ( B`, until the final byte
+    EscapeIntermediate,
+    /// In a CSI sequence, until its final byte
+    Csi,
+    /// In a CSI sequence that is not modeled, until its final byte
+    CsiIgnore,
+    /// In an OSC or other string sequence, until BEL or ST
+    String,
+    /// ESC inside a string sequence, possibly starting ST
+    StringEsc,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Cursor {
+    row: usize,
+    col: usize,
+}
+
+/// Character grid and cursor of a terminal, as the output leaves them
+///
+/// Covers what shells, pagers and editors use to draw: cursor movement, erasing, inserting
+/// and deleting, scroll regions, the alternate screen and double-width characters. Lines
+/// scrolled off the top are gone; the scrollback keeps the raw output for those.
+#[derive(Debug)]
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    grid: Vec<Vec<char>>,
+    /// The primary grid while the alternate screen is shown
+    primary: Option<Vec<Vec<char>>>,
+    cursor: Cursor,
+    /// The last column was just written: the next character goes to a new line
+    wrap_pending: bool,
+    /// DECSC / CSI s
+    saved_cursor: Option<Cursor>,
+    /// Cursor of the primary screen, saved by mode 1049
+    primary_cursor: Option<Cursor>,
+    /// Scroll region, first and last row
+    scroll_top: usize,
+    scroll_bottom: usize,
+    cursor_visible: bool,
+    autowrap: bool,
+    state: State,
+    private: bool,
+    params: Vec<u16>,
+    current: Option<u16>,
+    /// Partial UTF-8 character held back until the next chunk
+    utf8: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        let (cols, rows) = (usize::from(cols.max(1)), usize::from(rows.max(1)));
+        Self {
+            cols,
+            rows,
+            grid: blank_grid(cols, rows),
+            primary: None,
+            cursor: Cursor::default(),
+            wrap_pending: false,
+            saved_cursor: None,
+            primary_cursor: None,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            cursor_visible: true,
+            autowrap: true,
+            state: State::Ground,
+            private: false,
+            params: Vec::with_capacity(MAX_PARAMS),
+            current: None,
+            utf8: Vec::new(),
+        }
+    }
+
+    /// Lines of the screen, top to bottom, without trailing blanks
+    pub fn lines(&self) -> Vec<String> {
+        self.grid
+            .iter()
+            .map(|line| {
+                let text: String = line.iter().filter(|&&c| c != WIDE_TAIL).collect();
+                text.trim_end_matches(' ').to_string()
+            })
+            .collect()
+    }
+
+    /// Cursor position as (row, column), both from 0
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor.row, self.cursor.col)
+    }
+
+    pub fn cols(&self) -> u16 {
+        self.cols as u16
+    }
+
+    pub fn rows(&self) -> u16 {
+        self.rows as u16
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn alt_screen(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// Change the size; content is kept at the top left and the cursor stays on screen,
+    /// lines above it scrolling off when rows are removed
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        let (cols, rows) = (usize::from(cols.max(1)), usize::from(rows.max(1)));
+        let scrolled = (self.cursor.row + 1).saturating_sub(rows);
+        resize_grid(&mut self.grid, cols, rows, scrolled);
+        if let Some(primary) = self.primary.as_mut() {
+            resize_grid(primary, cols, rows, 0);
+        }
+        self.cols = cols;
+        self.rows = rows;
+        self.cursor = Cursor { row: self.cursor.row - scrolled, col: self.cursor.col.min(cols - 1) };
+        self.wrap_pending = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+    }
+
+    /// Apply a chunk of output; a sequence or character split across chunks is applied
+    /// once it is complete
+    pub fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.advance(byte);
+        }
+    }
+
+    fn advance(&mut self, byte: u8) {
+        match self.state {
+            State::Ground => self.ground(byte),
+            State::Escape => self.escape(byte),
+            State::EscapeIntermediate => match byte {
+                0x20..=0x2f => {}
+                0x1b => self.state = State::Escape,
+                _ => self.state = State::Ground,
+            },
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    let digit = u16::from(byte - b'0');
+                    self.current = Some(self.current.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                }
+                b';' | b':' => self.push_param(),
+                b'?' => self.private = true,
+                // Other prefixes (`CSI >`, `CSI =`) are keyboard and device queries
+                b'<'..=b'>' | 0x20..=0x2f => self.state = State::CsiIgnore,
+                0x40..=0x7e => {
+                    self.push_param();
+                    self.state = State::Ground;
+                    self.csi(byte);
+                }
+                0x1b => self.state = State::Escape,
+                0x18 | 0x1a => self.state = State::Ground,
+                // Control characters take effect in the middle of a sequence
+                0x00..=0x1f => self.control(byte),
+                _ => self.state = State::Ground,
+            },
+            State::CsiIgnore => match byte {
+                0x40..=0x7e | 0x18 | 0x1a => self.state = State::Ground,
+                0x1b => self.state = State::Escape,
+                _ => {}
+            },
+            State::String => match byte {
+                0x07 => self.state = State::Ground,
+                0x1b => self.state = State::StringEsc,
+                _ => {}
+            },
+            State::StringEsc => self.state = if byte == b'\\' { State::Ground } else { State::String },
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if !self.utf8.is_empty() || byte >= 0x80 {
+            self.utf8_byte(byte);
+            return;
+        }
+        match byte {
+            0x1b => self.state = State::Escape,
+            0x20..=0x7e => self.print(char::from(byte)),
+            _ => self.control(byte),
+        }
+    }
+
+    /// Collect a multi-byte character; a malformed one is shown as U+FFFD
+    fn utf8_byte(&mut self, byte: u8) {
+        if !self.utf8.is_empty() && byte & 0xc0 != 0x80 {
+            // The character ended early; the byte starts something new
+            self.utf8.clear();
+            self.print(char::REPLACEMENT_CHARACTER);
+            self.ground(byte);
+            return;
+        }
+        self.utf8.push(byte);
+        let expected = match self.utf8[0] {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => 1,
+        };
+        if self.utf8.len() < expected {
+            return;
+        }
+        let c = std::str::from_utf8(&self.utf8)
+            .ok()
+            .and_then(|text| text.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        self.utf8.clear();
+        self.print(c);
+    }
+
+    fn escape(&mut self, byte: u8) {
+        self.state = State::Ground;
+        match byte {
+            b'[' => {
+                self.params.clear();
+                self.current = None;
+                self.private = false;
+                self.state = State::Csi;
+            }
+            b']' | b'P' | b'X' | b'^' | b'_' => self.state = State::String,
+            0x20..=0x2f => self.state = State::EscapeIntermediate,
+            0x1b => self.state = State::Escape,
+            b'7' => self.saved_cursor = Some(self.cursor),
+            b'8' => self.restore_cursor(),
+            b'D' => self.line_feed(),
+            b'E' => {
+                self.carriage_return();
+                self.line_feed();
+            }
+            b'M' => self.reverse_index(),
+            b'c' => *self = Self::new(self.cols as u16, self.rows as u16),
+            _ => {}
+        }
+    }
+
+    fn control(&mut self, byte: u8) {
+        match byte {
+            0x08 => {
+                self.cursor.col = self.cursor.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            0x09 => {
+                self.cursor.col = ((self.cursor.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols - 1);
+                self.wrap_pending = false;
+            }
+            0x0a..=0x0c => self.line_feed(),
+            0x0d => self.carriage_return(),
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        let width = char_width(c);
+        if width == 0 {
+            return;
+        }
+        if self.wrap_pending || (width == 2 && self.cursor.col == self.cols - 1 && self.cols > 1) {
+            if self.autowrap {
+                self.carriage_return();
+                self.line_feed();
+            } else if width == 2 {
+                self.cursor.col = self.cols.saturating_sub(2);
+            }
+        }
+        let Cursor { row, col } = self.cursor;
+        self.put(row, col, c);
+        if width == 2 && col + 1 < self.cols {
+            self.put(row, col + 1, WIDE_TAIL);
+        }
+        if col + width >= self.cols {
+            self.cursor.col = self.cols - 1;
+            self.wrap_pending = self.autowrap;
+        } else {
+            self.cursor.col = col + width;
+            self.wrap_pending = false;
+        }
+    }
+
+    /// Write a cell, blanking the other half of a double-width character it overwrites
+    fn put(&mut self, row: usize, col: usize, c: char) {
+        let line = &mut self.grid[row];
+        if line[col] == WIDE_TAIL && col > 0 && c != WIDE_TAIL {
+            line[col - 1] = ' ';
+        }
+        if col + 1 < line.len() && line[col + 1] == WIDE_TAIL && c != WIDE_TAIL {
+            line[col + 1] = ' ';
+        }
+        line[col] = c;
+    }
+
+    fn carriage_return(&mut self) {
+        self.cursor.col = 0;
+        self.wrap_pending = false;
+    }
+
+    fn line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.row + 1 < self.rows {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor.row == self.scroll_top {
+            self.scroll_down(1);
+        } else {
+            self.cursor.row = self.cursor.row.saturating_sub(1);
+        }
+    }
+
+    /// Move the lines of the scroll region up, blank lines entering at its bottom
+    fn scroll_up(&mut self, count: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let count = count.min(bottom - top + 1);
+        self.grid[top..=bottom].rotate_left(count);
+        for line in &mut self.grid[bottom + 1 - count..=bottom] {
+            line.fill(' ');
+        }
+    }
+
+    /// Move the lines of the scroll region down, blank lines entering at its top
+    fn scroll_down(&mut self, count: usize) {
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let count = count.min(bottom - top + 1);
+        self.grid[top..=bottom].rotate_right(count);
+        for line in &mut self.grid[top..top + count] {
+            line.fill(' ');
+        }
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor.unwrap_or_default();
+        self.cursor = Cursor { row: saved.row.min(self.rows - 1), col: saved.col.min(self.cols - 1) };
+        self.wrap_pending = false;
+    }
+
+    fn push_param(&mut self) {
+        let value = self.current.take().unwrap_or(0);
+        if self.params.len() < MAX_PARAMS {
+            self.params.push(value);
+        }
+    }
+
+    /// Parameter `index`, or `default` when absent or 0
+    fn param(&self, index: usize, default: u16) -> usize {
+        usize::from(self.params.get(index).copied().filter(|&value| value != 0).unwrap_or(default))
+    }
+
+    fn csi(&mut self, final_byte: u8) {
+        if self.private {
+            if matches!(final_byte, b'h' | b'l') {
+                let params = std::mem::take(&mut self.params);
+                for &mode in &params {
+                    self.set_mode(mode, final_byte == b'h');
+                }
+                self.params = params;
+            }
+            return;
+        }
+        let n = self.param(0, 1);
+        let Cursor { row, col } = self.cursor;
+        self.wrap_pending = false;
+        match final_byte {
+            b'A' => self.cursor.row = row.saturating_sub(n).max(if row >= self.scroll_top { self.scroll_top } else { 0 }),
+            b'B' | b'e' => {
+                let limit = if row <= self.scroll_bottom { self.scroll_bottom } else { self.rows - 1 };
+                self.cursor.row = (row + n).min(limit);
+            }
+            b'C' | b'a' => self.cursor.col = (col + n).min(self.cols - 1),
+            b'D' => self.cursor.col = col.saturating_sub(n),
+            b'E' => self.cursor = Cursor { row: (row + n).min(self.rows - 1), col: 0 },
+            b'F' => self.cursor = Cursor { row: row.saturating_sub(n), col: 0 },
+            b'G' | b'`' => self.cursor.col = (n - 1).min(self.cols - 1),
+            b'd' => self.cursor.row = (n - 1).min(self.rows - 1),
+            b'H' | b'f' => {
+                self.cursor = Cursor {
+                    row: (self.param(0, 1) - 1).min(self.rows - 1),
+                    col: (self.param(1, 1) - 1).min(self.cols - 1),
+                };
+            }
+            b'J' => match self.params.first().copied().unwrap_or(0) {
+                0 => {
+                    self.erase(row, col, self.cols);
+                    self.erase_lines(row + 1, self.rows);
+                }
+                1 => {
+                    self.erase_lines(0, row);
+                    self.erase(row, 0, col + 1);
+                }
+                2 | 3 => self.erase_lines(0, self.rows),
+                _ => {}
+            },
+            b'K' => match self.params.first().copied().unwrap_or(0) {
+                0 => self.erase(row, col, self.cols),
+                1 => self.erase(row, 0, col + 1),
+                2 => self.erase(row, 0, self.cols),
+                _ => {}
+            },
+            b'X' => self.erase(row, col, (col + n).min(self.cols)),
+            b'@' => {
+                let line = &mut self.grid[row];
+                let n = n.min(self.cols - col);
+                line[col..].rotate_right(n);
+                line[col..col + n].fill(' ');
+            }
+            b'P' => {
+                let line = &mut self.grid[row];
+                let n = n.min(self.cols - col);
+                line[col..].rotate_left(n);
+                let len = line.len();
+                line[len - n..].fill(' ');
+            }
+            b'L' | b'M' if (self.scroll_top..=self.scroll_bottom).contains(&row) => {
+                let top = self.scroll_top;
+                self.scroll_top = row;
+                if final_byte == b'L' {
+                    self.scroll_down(n);
+                } else {
+                    self.scroll_up(n);
+                }
+                self.scroll_top = top;
+                self.cursor.col = 0;
+            }
+            b'S' => self.scroll_up(n),
+            b'T' => self.scroll_down(n),
+            b'r' => {
+                let top = self.param(0, 1) - 1;
+                let bottom = self.param(1, self.rows as u16).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.cursor = Cursor::default();
+                }
+            }
+            b's' if self.params.len() <= 1 => self.saved_cursor = Some(self.cursor),
+            b'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn set_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            7 => self.autowrap = enabled,
+            25 => self.cursor_visible = enabled,
+            47 | 1047 | 1049 => {
+                if enabled == self.alt_screen() {
+                    return;
+                }
+                if enabled {
+                    if mode == 1049 {
+                        self.primary_cursor = Some(self.cursor);
+                    }
+                    let alternate = blank_grid(self.cols, self.rows);
+                    self.primary = Some(std::mem::replace(&mut self.grid, alternate));
+                } else {
+                    if let Some(primary) = self.primary.take() {
+                        self.grid = primary;
+                    }
+                    if let Some(cursor) = self.primary_cursor.take() {
+                        self.cursor = cursor;
+                    }
+                }
+                self.wrap_pending = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        if from >= to {
+            return;
+        }
+        let line = &mut self.grid[row];
+        // Half a double-width character is not left behind
+        if line[from] == WIDE_TAIL && from > 0 {
+            line[from - 1] = ' ';
+        }
+        if to < line.len() && line[to] == WIDE_TAIL {
+            line[to] = ' ';
+        }
+        line[from..to].fill(' ');
+    }
+
+    fn erase_lines(&mut self, from: usize, to: usize) {
+        for line in &mut self.grid[from.min(to)..to] {
+            line.fill(' ');
+        }
+    }
+}
+
+fn blank_grid(cols: usize, rows: usize) -> Vec<Vec<char>> {
+    vec![vec![' '; cols]; rows]
+}
+
+/// Resize a grid, dropping `scrolled` lines from its top first
+fn resize_grid(grid: &mut Vec<Vec<char>>, cols: usize, rows: usize, scrolled: usize) {
+    grid.drain(..scrolled.min(grid.len()));
+    grid.resize(rows, vec![' '; cols]);
+    for line in grid.iter_mut() {
+        line.resize(cols, ' ');
+        // A double-width character cut in half by the right edge
+        if line.last().is_some_and(|&c| c != WIDE_TAIL && char_width(c) == 2) {
+            line[cols - 1] = ' ';
+        }
+        if line[0] == WIDE_TAIL {
+            line[0] = ' ';
+        }
+    }
+}
+
+/// Cells a character takes: 0 for combining marks and other zero-width characters, 2 for
+/// East Asian wide characters and emoji; an approximation of `wcwidth`
+fn char_width(c: char) -> usize {
+    match u32::from(c) {
+        0x0300..=0x036f | 0x1ab0..=0x1aff | 0x1dc0..=0x1dff | 0x200b..=0x200f | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f | 0x2e80..=0x303e | 0x3041..=0x33ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f | 0xff00..=0xff60
+        | 0xffe0..=0xffe6 | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(cols: u16, rows: u16, output: &[u8]) -> Screen {
+        let mut screen = Screen::new(cols, rows);
+        screen.feed(output);
+        screen
+    }
+
+    #[test]
+    fn test_text_wraps_and_scrolls() {
+        let screen = screen(5, 3, b"one\r\ntwo\r\nabcdefg\r\nend");
+        assert_eq!(screen.lines(), ["abcde", "fg", "end"]);
+        assert_eq!(screen.cursor(), (2, 3));
+
+        // Writing the last column leaves the cursor there until the next character
+        let screen = self::screen(5, 2, b"abcde");
+        assert_eq!(screen.lines(), ["abcde", ""]);
+        assert_eq!(screen.cursor(), (0, 4));
+    }
+
+    #[test]
+    fn test_cursor_movement_and_erase() {
+        let screen = screen(10, 3, b"hello\x1b[2;3Hxy\x1b[1;2H\x1b[K\x1b[3;1Hprompt$ ls\x1b[D\x1b[P");
+        assert_eq!(screen.lines(), ["h", "  xy", "prompt$ s"]);
+        assert_eq!(screen.cursor(), (2, 8));
+
+        let screen = self::screen(10, 3, b"aaa\r\nbbb\r\nccc\x1b[2;2H\x1b[J");
+        assert_eq!(screen.lines(), ["aaa", "b", ""]);
+        let screen = self::screen(10, 2, b"abc\x1b[2J\x1b[H\x1b[1;1Hz");
+        assert_eq!(screen.lines(), ["z", ""]);
+    }
+
+    #[test]
+    fn test_sequences_split_across_chunks() {
+        let mut screen = Screen::new(10, 2);
+        for chunk in [&b"\x1b["[..], b"2;", b"4Hx", b"\x1b]0;ti", b"tle\x07y", &[0xe4, 0xb8][..], &[0xad][..]] {
+            screen.feed(chunk);
+        }
+        assert_eq!(screen.lines(), ["", "   xy中"]);
+        assert_eq!(screen.cursor(), (1, 7));
+    }
+
+    #[test]
+    fn test_wide_characters_take_two_cells() {
+        let mut screen = screen(5, 2, "中文a中".as_bytes());
+        assert_eq!(screen.lines(), ["中文a", "中"]);
+        assert_eq!(screen.cursor(), (1, 2));
+
+        // Overwriting half of a wide character blanks the other half
+        screen.feed(b"\x1b[1;2Hx");
+        assert_eq!(screen.lines(), [" x文a", "中"]);
+    }
+
+    #[test]
+    fn test_scroll_region_and_line_operations() {
+        let mut screen = screen(4, 4, b"1\r\n2\r\n3\r\n4");
+        // Scroll region on rows 2-3: a line feed at its bottom scrolls only those rows
+        screen.feed(b"\x1b[2;3r\x1b[3;1H\nx");
+        assert_eq!(screen.lines(), ["1", "3", "x", "4"]);
+
+        screen.feed(b"\x1b[r\x1b[2;1H\x1b[L");
+        assert_eq!(screen.lines(), ["1", "", "3", "x"]);
+        screen.feed(b"\x1b[M\x1b[M");
+        assert_eq!(screen.lines(), ["1", "x", "", ""]);
+    }
+
+    #[test]
+    fn test_alternate_screen_restores_primary() {
+        let mut screen = screen(10, 2, b"$ vim\r\n");
+        screen.feed(b"\x1b[?1049h\x1b[?25l\x1b[Hediting");
+        assert!(screen.alt_screen());
+        assert!(!screen.cursor_visible());
+        assert_eq!(screen.lines(), ["editing", ""]);
+
+        screen.feed(b"\x1b[?1049l\x1b[?25h");
+        assert!(!screen.alt_screen());
+        assert!(screen.cursor_visible());
+        assert_eq!(screen.lines(), ["$ vim", ""]);
+        assert_eq!(screen.cursor(), (1, 0));
+    }
+
+    #[test]
+    fn test_resize_keeps_cursor_on_screen() {
+        let mut screen = screen(6, 4, b"a\r\nb\r\nc\r\nlonger");
+        screen.resize(3, 2);
+        assert_eq!(screen.lines(), ["c", "lon"]);
+        assert_eq!(screen.cursor(), (1, 2));
+
+        screen.resize(4, 3);
+        assert_eq!(screen.lines(), ["c", "lon", ""]);
+        screen.feed(b"\r\n\n\nend");
+        assert_eq!(screen.lines(), ["", "", "end"]);
+    }
+
+    #[test]
+    fn test_colors_and_malformed_input_leave_text() {
+        let screen = screen(10, 1, b"\x1b[1;31mred\x1b[0m \xff!");
+        assert_eq!(screen.lines(), ["red \u{fffd}!"]);
+    }
+}
@@ -0,0 +1,209 @@
+// Scrollback ring buffer
+// Keeps the most recent output of a session, bounded by a byte cap and optionally a line cap
+
+use std::collections::VecDeque;
+
+/// Bounded buffer of recent session output
+#[derive(Debug)]
+pub struct Scrollback {
+    buf: VecDeque<u8>,
+    max_bytes: usize,
+    /// Most newlines kept; `None` trims by bytes alone
+    max_lines: Option<usize>,
+    /// Stream position of the first buffered byte
+    start: u64,
+    /// Stream positions just after each buffered newline, tracked only with a line cap
+    line_starts: VecDeque<u64>,
+}
+
+impl Scrollback {
+    /// Create a buffer keeping at most `max_bytes`; 0 keeps nothing
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            buf: VecDeque::new(),
+            max_bytes,
+            max_lines: None,
+            start: 0,
+            line_starts: VecDeque::new(),
+        }
+    }
+
+    /// Also keep at most `max_lines` complete lines, plus the line still being written
+    ///
+    /// With a line cap the buffer is always trimmed at a line boundary, whichever cap is
+    /// exceeded, so its contents never start mid-line. Only a line still being written
+    /// that alone exceeds the byte cap is cut, keeping its tail.
+    pub fn with_max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = Some(max_lines);
+        self
+    }
+
+    /// Append output, dropping the oldest bytes beyond the cap
+    pub fn push(&mut self, data: &[u8]) {
+        let Some(max_lines) = self.max_lines else {
+            if data.len() >= self.max_bytes {
+                self.start += (self.buf.len() + data.len() - self.max_bytes) as u64;
+                self.buf.clear();
+                self.buf.extend(&data[data.len() - self.max_bytes..]);
+                return;
+            }
+            let overflow = (self.buf.len() + data.len()).saturating_sub(self.max_bytes);
+            self.buf.drain(..overflow);
+            self.start += overflow as u64;
+            self.buf.extend(data);
+            return;
+        };
+
+        let end = self.end();
+        for (i, _) in data.iter().enumerate().filter(|(_, &b)| b == b'\n') {
+            self.line_starts.push_back(end + i as u64 + 1);
+        }
+        self.buf.extend(data);
+
+        if self.line_starts.len() > max_lines {
+            let keep_from = self.line_starts[self.line_starts.len() - max_lines - 1];
+            self.trim_to(keep_from);
+        }
+        if self.buf.len() > self.max_bytes {
+            let cut = self.end() - self.max_bytes as u64;
+            let boundary = self.line_starts.iter().copied().find(|&pos| pos >= cut);
+            self.trim_to(boundary.unwrap_or(cut));
+        }
+    }
+
+    /// Stream position of the first buffered byte; output before it was dropped
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Stream position just after the last buffered byte
+    pub fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    /// Drop the buffered bytes before stream position `pos`
+    fn trim_to(&mut self, pos: u64) {
+        self.buf.drain(..(pos - self.start) as usize);
+        self.start = pos;
+        // A line starting exactly at `pos` lost its newline
+        while self.line_starts.front().is_some_and(|&line_start| line_start <= pos) {
+            self.line_starts.pop_front();
+        }
+    }
+
+    /// Drop all buffered output; returns how many bytes were dropped
+    pub fn clear(&mut self) -> usize {
+        let len = self.buf.len();
+        self.trim_to(self.end());
+        len
+    }
+
+    /// Copy of the buffered output, oldest first
+    pub fn contents(&self) -> Vec<u8> {
+        self.buf.iter().copied().collect()
+    }
+
+    /// Copy of up to `max_len` buffered bytes from stream position `offset`
+    ///
+    /// An offset before the buffer starts at its first byte, one past the end returns
+    /// nothing. Returns the position the copy actually starts at.
+    pub fn range(&self, offset: u64, max_len: usize) -> (u64, Vec<u8>) {
+        let from = offset.clamp(self.start, self.end());
+        let skip = (from - self.start) as usize;
+        (from, self.buf.iter().skip(skip).take(max_len).copied().collect())
+    }
+
+    /// Number of buffered bytes
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(scrollback: &Scrollback) -> Vec<u8> {
+        scrollback.contents()
+    }
+
+    #[test]
+    fn test_keeps_most_recent_bytes() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"hello ");
+        scrollback.push(b"world");
+        assert_eq!(contents(&scrollback), b"lo world");
+
+        scrollback.push(b"0123456789");
+        assert_eq!(contents(&scrollback), b"23456789");
+    }
+
+    #[test]
+    fn test_range_uses_stream_positions() {
+        let mut scrollback = Scrollback::new(8);
+        scrollback.push(b"hello world");
+        assert_eq!((scrollback.start(), scrollback.end()), (3, 11));
+        assert_eq!(scrollback.range(5, 3), (5, b" wo".to_vec()));
+        // Dropped output is skipped, reading past the end gives nothing
+        assert_eq!(scrollback.range(0, 4), (3, b"lo w".to_vec()));
+        assert_eq!(scrollback.range(9, 100), (9, b"ld".to_vec()));
+        assert_eq!(scrollback.range(42, 4), (11, Vec::new()));
+    }
+
+    #[test]
+    fn test_clear_reports_dropped_bytes() {
+        let mut scrollback = Scrollback::new(64);
+        scrollback.push(b"some output");
+        assert_eq!(scrollback.clear(), 11);
+        assert_eq!(scrollback.len(), 0);
+
+        scrollback.push(b"after");
+        assert_eq!(contents(&scrollback), b"after");
+    }
+
+    #[test]
+    fn test_zero_cap_keeps_nothing() {
+        let mut scrollback = Scrollback::new(0);
+        scrollback.push(b"ignored");
+        assert_eq!(scrollback.len(), 0);
+    }
+
+    #[test]
+    fn test_line_cap_keeps_most_recent_lines() {
+        let mut scrollback = Scrollback::new(1024).with_max_lines(2);
+        scrollback.push(b"one\r\ntwo\r\nthr");
+        assert_eq!(contents(&scrollback), b"one\r\ntwo\r\nthr");
+        // Lines split across pushes count once they end
+        scrollback.push(b"ee\r\nfour\r\n");
+        assert_eq!(contents(&scrollback), b"three\r\nfour\r\n");
+        scrollback.push(b"five\nsix\nseven\n");
+        assert_eq!(contents(&scrollback), b"six\nseven\n");
+
+        assert_eq!(scrollback.clear(), 10);
+        scrollback.push(b"a\nb\nc");
+        assert_eq!(contents(&scrollback), b"a\nb\nc");
+    }
+
+    #[test]
+    fn test_byte_cap_trims_at_line_boundary_with_line_cap() {
+        let mut scrollback = Scrollback::new(10).with_max_lines(100);
+        scrollback.push(b"first\nsecond\n");
+        // Keeping the last 10 bytes would start mid-line; the whole line goes instead
+        assert_eq!(contents(&scrollback), b"second\n");
+        scrollback.push(b"3rd\n");
+        assert_eq!(contents(&scrollback), b"3rd\n");
+
+        // A line still being written that alone exceeds the cap keeps its tail
+        scrollback.push(b"0123456789abcdef");
+        assert_eq!(contents(&scrollback), b"6789abcdef");
+        scrollback.push(b"\nok\n");
+        assert_eq!(contents(&scrollback), b"ok\n");
+    }
+
+    #[test]
+    fn test_byte_cap_alone_trims_mid_line() {
+        let mut scrollback = Scrollback::new(10);
+        scrollback.push(b"first\nsecond\n");
+        assert_eq!(contents(&scrollback), b"st\nsecond\n");
+    }
+}
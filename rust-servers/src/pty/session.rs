@@ -1,43 +1,296 @@
 // PTY session management
 
 use portable_pty::{native_pty_system, Child, MasterPty, PtySize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
 
 /// PTY session
 pub struct PtySession {
     master: Box<dyn MasterPty + Send>,
     child: Arc<Mutex<Box<dyn Child + Send + Sync>>>,
+    /// Program the shell was launched with
+    shell_program: String,
+    /// Shell type that was launched, after any fallback
+    shell_type: &'static str,
+    /// Send SIGWINCH to the foreground process group after each resize
+    #[cfg(unix)]
+    explicit_sigwinch: bool,
+    /// Memory cap of the shell and its children; lives as long as the session
+    memory_cap: Option<Arc<super::memory_limit::MemoryCap>>,
 }
 
 /// PTY reader (independent, no lock required)
 pub struct PtyReader {
     reader: Box<dyn Read + Send>,
+    /// Lets `read` be interrupted even while the child keeps the PTY open
+    #[cfg(unix)]
+    interrupt: unix_interrupt::ReadInterrupt,
+    canceller: ReadCanceller,
 }
 
+/// Handle that makes a blocked [`PtyReader::read`] return end of file
+///
+/// On Windows the read ends when the ConPTY closes, so cancelling is a no-op there.
+#[derive(Clone)]
+pub struct ReadCanceller {
+    #[cfg(unix)]
+    inner: Arc<unix_interrupt::Canceller>,
+}
+
+impl ReadCanceller {
+    /// Interrupt the current and all later reads
+    pub fn cancel(&self) {
+        #[cfg(unix)]
+        self.inner.cancel();
+    }
+}
+
+/// Handle on the shell process that does not need the session lock
+#[derive(Clone)]
+pub struct ChildHandle {
+    child: Arc<Mutex<Box<dyn Child + Send + Sync>>>,
+}
+
+impl ChildHandle {
+    /// Exit code if the process has exited, without blocking
+    ///
+    /// A process terminated by a signal reports 1.
+    pub fn try_exit_code(&self) -> Option<u32> {
+        self.try_exit_status().map(|status| status.code)
+    }
+
+    /// How the process ended, if it has exited, without blocking
+    pub fn try_exit_status(&self) -> Option<ShellExit> {
+        let mut child = self.child.lock().ok()?;
+        let status = child.try_wait().ok().flatten()?;
+        Some(ShellExit {
+            code: status.exit_code(),
+            #[cfg(unix)]
+            signal: status.signal().and_then(signal_number),
+            #[cfg(not(unix))]
+            signal: None,
+        })
+    }
+
+    /// Kill the process outright (SIGKILL on Unix), unless it has already exited
+    ///
+    /// Used when the regular hang-up based kill cannot run or failed.
+    pub fn force_kill(&self) -> std::io::Result<()> {
+        let mut child = self
+            .child
+            .lock()
+            .map_err(|_| std::io::Error::other("child lock poisoned"))?;
+        // A reaped process id may already belong to someone else
+        if matches!(child.try_wait(), Ok(Some(_))) {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            let Some(pid) = child.process_id() else {
+                return Ok(());
+            };
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        {
+            // Windows kills terminate the process already
+            child.kill()
+        }
+    }
+}
+
+/// How the shell process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShellExit {
+    /// Exit code; 1 for a process terminated by a signal
+    pub code: u32,
+    /// Number of the signal that terminated the process (Unix only)
+    pub signal: Option<i32>,
+}
+
+/// Signal number for a description from portable-pty, which only keeps the strsignal text
+#[cfg(unix)]
+fn signal_number(description: &str) -> Option<i32> {
+    if let Some(number) = description.strip_prefix("Signal ") {
+        return number.parse().ok();
+    }
+    (1..65).find(|&signal| {
+        let name = unsafe { libc::strsignal(signal) };
+        !name.is_null() && unsafe { std::ffi::CStr::from_ptr(name) }.to_bytes() == description.as_bytes()
+    })
+}
+
+/// Writes the writer thread may have queued before further writes fail right away
+const WRITE_QUEUE_LEN: usize = 64;
+
+/// How long a write may wait for the shell unless [`PtyWriter::set_timeout`] says otherwise
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// PTY writer (independent, no lock required)
+///
+/// The blocking writes run on a thread of the session, in the order they were queued,
+/// so a shell that stops reading its input (a full PTY buffer) cannot stall the caller.
 pub struct PtyWriter {
-    writer: Box<dyn Write + Send>,
+    queue: std::sync::mpsc::SyncSender<WriteJob>,
+    /// Coalesced input not written yet; always written before newer data
+    pending: Vec<u8>,
+    /// Write calls made to the PTY
+    writes: u64,
+    timeout: Duration,
+}
+
+/// Data for the writer thread and where to report the result
+struct WriteJob {
+    data: Vec<u8>,
+    done: tokio::sync::oneshot::Sender<std::io::Result<()>>,
+}
+
+/// Why a write did not reach the PTY
+#[derive(Debug, Error)]
+pub enum WriteError {
+    /// The shell did not take the input in time; queued data is still written, in order,
+    /// once the shell reads again, data refused by a full queue is not
+    #[error("the shell did not accept input in time")]
+    Timeout,
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("PTY writer stopped")]
+    Closed,
+}
+
+/// A queued write; [`wait`](Self::wait) for the shell to accept it
+#[must_use]
+pub struct PendingWrite {
+    /// `None` when nothing was queued
+    done: Result<Option<tokio::sync::oneshot::Receiver<std::io::Result<()>>>, WriteError>,
+    /// Fixed when queued, so waiting on several writes in turn takes one timeout at most
+    deadline: tokio::time::Instant,
+}
+
+/// Parameters used to spawn the shell of a PTY session
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    /// Shell type (cmd, powershell, wsl, bash, zsh, tmux, custom:/path)
+    pub shell_type: Option<String>,
+    /// Shell startup arguments
+    pub shell_args: Option<Vec<String>>,
+    /// Program and arguments spawned instead of a shell; excludes `shell_type`,
+    /// `shell_args` and the shell startup options
+    pub command: Option<Vec<String>>,
+    /// Working directory
+    pub cwd: Option<String>,
+    /// Environment variables
+    pub env: Option<HashMap<String, String>>,
+    /// Request login-shell behavior for the resolved shell
+    pub login: bool,
+    /// Skip the user's startup files; excludes `login` and `shell_integration`
+    pub no_rc: bool,
+    /// Inherit only [`super::shell::CLEAN_ENV_ALLOWLIST`] of the server's environment
+    pub clean_env: bool,
+    /// Load the OSC 133 shell integration script (bash, zsh, fish, PowerShell)
+    pub shell_integration: bool,
+    /// Send stderr through a separate pipe instead of the PTY (Unix only)
+    ///
+    /// stderr is then not a terminal: `isatty(2)` is false, so programs may drop colors
+    /// or progress output there, and the TTY settings do not apply to it.
+    pub separate_stderr: bool,
+    /// Run the shell with other credentials (Unix only, needs a root server)
+    pub run_as: Option<super::credentials::Credentials>,
+    /// Server descriptor handed to the shell under the same number (Unix only)
+    pub inherit_fd: Option<super::inherit_fd::InheritFd>,
+    /// WSL distribution to start instead of the default one (`wsl` shell type)
+    pub wsl_distro: Option<String>,
+    /// User to start the WSL distribution as (`wsl` shell type)
+    pub wsl_user: Option<String>,
+    /// Also send SIGWINCH to the foreground process group after each resize (Unix only)
+    ///
+    /// The resize ioctl already signals the group on Linux and macOS, but only when the
+    /// size changes; this is a fallback for setups where the signal does not arrive.
+    pub explicit_sigwinch: bool,
+    /// Cap the memory of the shell and everything it starts (Linux cgroup v2, Windows job
+    /// object); a fresh cgroup or job is set up for every spawn
+    pub memory_limit: Option<super::memory_limit::MemoryLimit>,
+}
+
+/// Why a PTY session could not be created
+///
+/// `detail` fields carry the underlying OS error text.
+#[derive(Debug, Error)]
+pub enum SpawnError {
+    #[error("无法分配 PTY: {0}")]
+    PtyAlloc(String),
+    #[error("工作目录不存在: {path}")]
+    CwdNotFound { path: String, detail: String },
+    #[error("找不到 shell: {program}")]
+    ShellNotFound { program: String, detail: String },
+    #[error("启动 shell 失败: {0}")]
+    SpawnFailed(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<std::io::Error> for SpawnError {
+    fn from(e: std::io::Error) -> Self {
+        SpawnError::Other(e.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for SpawnError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        SpawnError::Other(e.to_string())
+    }
+}
+
+/// Terminal settings the termios message may read and change
+pub const TERMIOS_FLAGS: &[&str] = &["ixon", "ixoff", "icrnl", "opost", "icanon", "echo", "isig", "iexten"];
+
+/// Check that `cwd` is an existing directory; the error describes why not
+pub fn check_cwd(cwd: &str) -> Result<(), String> {
+    match std::fs::metadata(cwd) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => Err(std::io::Error::from(std::io::ErrorKind::NotADirectory).to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// TERM value a session runs with
+///
+/// Priority: user-provided value > system environment variable > xterm-256color
+pub fn resolve_term(env: Option<&HashMap<String, String>>) -> String {
+    env.and_then(|e| e.get("TERM").cloned())
+        .or_else(|| std::env::var("TERM").ok())
+        .unwrap_or_else(|| "xterm-256color".to_string())
 }
 
 impl PtySession {
-    /// Create a new PTY session and return (session, reader, writer)
+    /// Create a new PTY session and return (session, reader, writer, stderr reader)
+    ///
+    /// The stderr reader is only present with [`SpawnOptions::separate_stderr`].
     /// 
     /// # Parameters
     /// - `cols`: Terminal column count
     /// - `rows`: Terminal row count
-    /// - `shell_type`: Optional shell type (cmd, powershell, wsl, bash, zsh, tmux, custom:/path)
-    /// - `shell_args`: Optional shell startup arguments
-    /// - `cwd`: Optional working directory
-    /// - `env`: Optional environment variables
+    /// - `options`: Shell, arguments, working directory and environment to spawn with
+    #[allow(clippy::type_complexity)]
     pub fn new(
         cols: u16, 
         rows: u16, 
-        shell_type: Option<&str>,
-        shell_args: Option<&[String]>,
-        cwd: Option<&str>,
-        env: Option<&std::collections::HashMap<String, String>>
-    ) -> Result<(Self, PtyReader, PtyWriter), Box<dyn std::error::Error>> {
+        options: &SpawnOptions,
+    ) -> Result<(Self, PtyReader, PtyWriter, Option<StderrReader>), SpawnError> {
+        let cwd = options.cwd.as_deref();
+        let env = options.env.as_ref();
+
+        // portable-pty silently starts in the home directory when cwd is unusable
+        if let Some(cwd) = cwd {
+            check_cwd(cwd).map_err(|detail| SpawnError::CwdNotFound { path: cwd.to_string(), detail })?;
+        }
+
         // Get the PTY system
         let pty_system = native_pty_system();
         
@@ -47,18 +300,123 @@ impl PtySession {
             cols,
             pixel_width: 0,
             pixel_height: 0,
-        })?;
+        }).map_err(|e| SpawnError::PtyAlloc(format!("{:#}", e)))?;
         
-        // Get the command for the requested shell type
-        let mut cmd = super::shell::get_shell_by_type(shell_type);
+        // Get the command for the requested shell type, or the program to run
+        let resolved = match &options.command {
+            Some(argv) => super::shell::get_program_command(argv),
+            None => super::shell::get_shell_by_type(options.shell_type.as_deref()),
+        };
+        let shell_program = resolved.program;
+        let shell_type = resolved.shell_type;
+        let mut cmd = resolved.command;
+        if shell_type == "wsl" {
+            super::shell::append_wsl_args(&mut cmd, options.wsl_distro.as_deref(), options.wsl_user.as_deref());
+        }
         
-        // Add startup arguments
-        if let Some(args) = shell_args {
-            for arg in args {
-                cmd.arg(arg);
+        // Unsupported shells are launched unchanged
+        let injection = if options.shell_integration {
+            super::shell_integration::prepare(&shell_program, options.login, options.shell_args.is_some(), env)
+        } else {
+            None
+        };
+
+        // Add login, integration and startup arguments
+        match &injection {
+            Some(injection) => {
+                let login = options.login && !injection.handles_login;
+                super::shell::append_shell_args(&mut cmd, None, login);
+                for arg in &injection.args {
+                    cmd.arg(arg);
+                }
+                super::shell::append_shell_args(&mut cmd, options.shell_args.as_deref(), false);
+            }
+            None => {
+                if options.no_rc {
+                    super::shell::append_norc_args(&mut cmd);
+                }
+                super::shell::append_shell_args(&mut cmd, options.shell_args.as_deref(), options.login);
             }
         }
-        
+
+        // Switch credentials in a helper between the wrappers and the shell, so the
+        // stderr FIFO below is still opened with the server's permissions
+        #[cfg(unix)]
+        let run_as = options.run_as.as_ref().filter(|creds| !creds.is_current());
+        #[cfg(unix)]
+        if let Some(creds) = run_as {
+            cmd = creds.wrap(&cmd);
+            // Give the terminal device to the user like login does
+            if let Some(tty) = pair.master.tty_name() {
+                use std::os::unix::ffi::OsStrExt;
+
+                let path = std::ffi::CString::new(tty.as_os_str().as_bytes())
+                    .map_err(|e| SpawnError::Other(e.to_string()))?;
+                if unsafe { libc::chown(path.as_ptr(), creds.uid, libc::gid_t::MAX) } != 0 {
+                    return Err(SpawnError::Other(format!("无法修改终端设备属主: {}", std::io::Error::last_os_error())));
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        if options.run_as.is_some() {
+            return Err(SpawnError::Other("uid/gid/username 仅在 Unix 上可用".to_string()));
+        }
+
+        // Collect the extra descriptor in a helper outside the credentials switch, so it
+        // is received with the server's permissions and stays open through that exec
+        #[cfg(unix)]
+        let handoff = match &options.inherit_fd {
+            Some(inherit) => {
+                let handoff = super::inherit_fd::Handoff::listen(inherit.fd)
+                    .map_err(|e| SpawnError::Other(format!("无法创建描述符交接套接字: {}", e)))?;
+                cmd = inherit.wrap(&handoff.socket_path(), &cmd);
+                Some(handoff)
+            }
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if options.inherit_fd.is_some() {
+            return Err(SpawnError::Other("inherit_fd 仅在 Unix 上可用".to_string()));
+        }
+
+        // Redirect stderr into a FIFO; portable-pty closes inherited descriptors, so the
+        // child opens it by path
+        #[cfg(unix)]
+        let stderr_reader = if options.separate_stderr {
+            let reader = StderrReader::create()?;
+            let mut wrapper = portable_pty::CommandBuilder::new("/bin/sh");
+            wrapper.args(["-c", "f=$1; shift; exec \"$@\" 2>\"$f\"", "termy-stderr"]);
+            wrapper.arg(&reader.path);
+            for arg in cmd.get_argv() {
+                wrapper.arg(arg);
+            }
+            cmd = wrapper;
+            Some(reader)
+        } else {
+            None
+        };
+        #[cfg(not(unix))]
+        let stderr_reader: Option<StderrReader> = if options.separate_stderr {
+            return Err(SpawnError::Other("separate_stderr 仅在 Unix 上可用".to_string()));
+        } else {
+            None
+        };
+
+        // Join the cgroup before any other helper runs, so every process of the session is in it
+        let memory_cap = match &options.memory_limit {
+            Some(limit) => Some(limit.create().map_err(|e| SpawnError::Other(format!("无法设置内存上限: {}", e)))?),
+            None => None,
+        };
+        #[cfg(target_os = "linux")]
+        if let Some(cap) = &memory_cap {
+            cmd = cap.wrap(&cmd);
+        }
+
+        // Start from a minimal environment instead of the server's
+        if options.clean_env {
+            super::shell::clear_env(&mut cmd);
+        }
+
         // Set the working directory
         if let Some(cwd_path) = cwd {
             cmd.cwd(cwd_path);
@@ -66,11 +424,7 @@ impl PtySession {
         
         // Set environment variables
         // Ensure the TERM environment variable exists, otherwise commands like clear and vim will not work correctly
-        let term_value = env
-            .and_then(|e| e.get("TERM").cloned())
-            .or_else(|| std::env::var("TERM").ok())
-            .unwrap_or_else(|| "xterm-256color".to_string());
-        cmd.env("TERM", term_value);
+        cmd.env("TERM", resolve_term(env));
         
         // Set UTF-8 locale environment variables so non-ASCII characters display correctly
         // Priority: user-provided value > system environment variable > UTF-8 default value
@@ -95,35 +449,254 @@ impl PtySession {
                 }
             }
         }
+        // The account's own identity, unless the client set it explicitly
+        #[cfg(unix)]
+        if let Some(creds) = run_as {
+            let unset = |key: &str| env.is_none_or(|e| !e.contains_key(key));
+            if let (Some(home), true) = (&creds.home, unset("HOME")) {
+                cmd.env("HOME", home);
+            }
+            if let Some(user) = &creds.user {
+                for key in ["USER", "LOGNAME"] {
+                    if unset(key) {
+                        cmd.env(key, user);
+                    }
+                }
+            }
+        }
+        if let Some(injection) = &injection {
+            for (key, value) in &injection.env {
+                cmd.env(key, value);
+            }
+        }
         // Start the shell process
-        let child = pair.slave.spawn_command(cmd)?;
+        #[allow(unused_mut)]
+        let mut child = pair.slave.spawn_command(cmd).map_err(|e| {
+            let detail = format!("{:#}", e);
+            if super::shell::is_launchable(&shell_program) {
+                SpawnError::SpawnFailed(detail)
+            } else {
+                SpawnError::ShellNotFound { program: shell_program.clone(), detail }
+            }
+        })?;
+        #[cfg(unix)]
+        if let Some(handoff) = handoff {
+            if let Err(e) = handoff.send(|| child.try_wait().ok().flatten().is_some()) {
+                let _ = child.kill();
+                return Err(SpawnError::Other(format!("无法交接文件描述符: {}", e)));
+            }
+        }
+        #[cfg(windows)]
+        if let Some(cap) = &memory_cap {
+            let assigned = child.process_id().ok_or_else(|| "shell has no process id".to_string()).and_then(|pid| cap.assign(pid));
+            if let Err(e) = assigned {
+                let _ = child.kill();
+                return Err(SpawnError::Other(format!("无法设置内存上限: {}", e)));
+            }
+        }
         
         // Get the reader and writer (independent, no lock required)
-        let reader = PtyReader {
-            reader: pair.master.try_clone_reader()?,
+        #[cfg(unix)]
+        let reader = {
+            use std::os::fd::AsRawFd;
+
+            let master_fd = pair
+                .master
+                .as_raw_fd()
+                .ok_or_else(|| SpawnError::Other("PTY 没有可用的文件描述符".to_string()))?;
+            let file = unix_interrupt::dup_fd(master_fd)?;
+            let (interrupt, canceller) = unix_interrupt::ReadInterrupt::new(file.as_raw_fd())?;
+            PtyReader {
+                reader: Box::new(file),
+                interrupt,
+                canceller: ReadCanceller { inner: Arc::new(canceller) },
+            }
         };
-        let writer = PtyWriter {
-            writer: pair.master.take_writer()?,
+        #[cfg(not(unix))]
+        let reader = PtyReader {
+            reader: pair.master.try_clone_reader().map_err(|e| SpawnError::Other(format!("{:#}", e)))?,
+            canceller: ReadCanceller {},
         };
+        let writer = PtyWriter::new(pair.master.take_writer().map_err(|e| SpawnError::Other(format!("{:#}", e)))?)?;
         
         let session = Self {
             master: pair.master,
             child: Arc::new(Mutex::new(child)),
+            shell_program,
+            shell_type,
+            #[cfg(unix)]
+            explicit_sigwinch: options.explicit_sigwinch,
+            memory_cap: memory_cap.map(Arc::new),
         };
         
-        Ok((session, reader, writer))
+        Ok((session, reader, writer, stderr_reader))
     }
 
     /// Resize the PTY
-    pub fn resize(&mut self, cols: u16, rows: u16) -> Result<(), Box<dyn std::error::Error>> {
+    ///
+    /// `width_px`/`height_px` fill the winsize pixel fields that sixel and other image
+    /// protocols query; 0 leaves them unset.
+    pub fn resize(&mut self, cols: u16, rows: u16, width_px: u16, height_px: u16) -> Result<(), Box<dyn std::error::Error>> {
         self.master.resize(PtySize {
             rows,
             cols,
-            pixel_width: 0,
-            pixel_height: 0,
+            pixel_width: width_px,
+            pixel_height: height_px,
         })?;
+        #[cfg(unix)]
+        if self.explicit_sigwinch {
+            self.signal_winch();
+        }
         Ok(())
     }
+
+    /// Send SIGWINCH to the foreground process group of the terminal
+    ///
+    /// The new size took effect either way, so a group that is gone is not an error.
+    #[cfg(unix)]
+    fn signal_winch(&self) {
+        let Some(fd) = self.master_fd() else { return };
+        let pgid = unsafe { libc::tcgetpgrp(fd) };
+        if pgid > 0 {
+            unsafe { libc::killpg(pgid, libc::SIGWINCH) };
+        }
+    }
+    
+    /// Apply termios flag changes to the PTY and return every flag of [`TERMIOS_FLAGS`]
+    ///
+    /// Names outside [`TERMIOS_FLAGS`] are rejected before anything changes.
+    #[cfg(unix)]
+    pub fn termios(
+        &self,
+        changes: &HashMap<String, bool>,
+    ) -> Result<std::collections::BTreeMap<&'static str, bool>, Box<dyn std::error::Error>> {
+        fn flag_bits<'a>(termios: &'a mut libc::termios, name: &str) -> Option<(&'a mut libc::tcflag_t, libc::tcflag_t)> {
+            Some(match name {
+                "ixon" => (&mut termios.c_iflag, libc::IXON),
+                "ixoff" => (&mut termios.c_iflag, libc::IXOFF),
+                "icrnl" => (&mut termios.c_iflag, libc::ICRNL),
+                "opost" => (&mut termios.c_oflag, libc::OPOST),
+                "icanon" => (&mut termios.c_lflag, libc::ICANON),
+                "echo" => (&mut termios.c_lflag, libc::ECHO),
+                "isig" => (&mut termios.c_lflag, libc::ISIG),
+                "iexten" => (&mut termios.c_lflag, libc::IEXTEN),
+                _ => return None,
+            })
+        }
+
+        if let Some(name) = changes.keys().find(|name| !TERMIOS_FLAGS.contains(&name.as_str())) {
+            return Err(format!("未知的 termios 标志: {}", name).into());
+        }
+        let fd = self.master.as_raw_fd().ok_or("PTY 没有可用的文件描述符")?;
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if !changes.is_empty() {
+            for (name, &enabled) in changes {
+                if let Some((bits, mask)) = flag_bits(&mut termios, name) {
+                    if enabled {
+                        *bits |= mask;
+                    } else {
+                        *bits &= !mask;
+                    }
+                }
+            }
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            // Read back what the driver actually applied
+            if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(TERMIOS_FLAGS
+            .iter()
+            .filter_map(|&name| flag_bits(&mut termios, name).map(|(bits, mask)| (name, *bits & mask != 0)))
+            .collect())
+    }
+
+    #[cfg(not(unix))]
+    pub fn termios(
+        &self,
+        _changes: &HashMap<String, bool>,
+    ) -> Result<std::collections::BTreeMap<&'static str, bool>, Box<dyn std::error::Error>> {
+        Err("termios 仅在 Unix 上可用".into())
+    }
+
+    /// File descriptor of the PTY master
+    #[cfg(unix)]
+    pub fn master_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.master.as_raw_fd()
+    }
+
+    /// Bytes the shell wrote that the reader has not read yet; `None` where the PTY
+    /// cannot tell (Windows)
+    #[cfg(unix)]
+    pub fn pending_output(&self) -> Option<usize> {
+        let fd = self.master.as_raw_fd()?;
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(fd, libc::FIONREAD, &mut pending) } != 0 {
+            return None;
+        }
+        Some(pending.max(0) as usize)
+    }
+
+    #[cfg(not(unix))]
+    pub fn pending_output(&self) -> Option<usize> {
+        None
+    }
+
+    /// Process id of the shell
+    pub fn child_pid(&self) -> Option<u32> {
+        self.child.lock().ok()?.process_id()
+    }
+
+    /// Process running in the foreground of the terminal, if it can be determined
+    pub fn foreground(&self) -> Option<super::process::ForegroundProcess> {
+        #[cfg(unix)]
+        {
+            super::process::foreground(self.master_fd()?)
+        }
+        #[cfg(windows)]
+        {
+            super::process::foreground(self.child_pid()?)
+        }
+    }
+
+    /// Handle for collecting the exit code or force-killing the shell
+    pub fn child_handle(&self) -> ChildHandle {
+        ChildHandle {
+            child: Arc::clone(&self.child),
+        }
+    }
+
+    /// Program the shell was launched with
+    pub fn shell_program(&self) -> &str {
+        &self.shell_program
+    }
+
+    /// Shell type that was launched, after any fallback (see [`super::shell::ResolvedShell`])
+    pub fn resolved_shell_type(&self) -> &'static str {
+        self.shell_type
+    }
+
+    /// Memory cap the shell runs under, with [`SpawnOptions::memory_limit`]
+    pub fn memory_cap(&self) -> Option<Arc<super::memory_limit::MemoryCap>> {
+        self.memory_cap.clone()
+    }
+
+    /// Get the current PTY size as (cols, rows)
+    pub fn size(&self) -> Result<(u16, u16), Box<dyn std::error::Error>> {
+        let size = self.master.get_size()?;
+        Ok((size.cols, size.rows))
+    }
+
+    /// Get the current PTY size in pixels as (width, height); (0, 0) when unset
+    pub fn pixel_size(&self) -> Result<(u16, u16), Box<dyn std::error::Error>> {
+        let size = self.master.get_size()?;
+        Ok((size.pixel_width, size.pixel_height))
+    }
     
     /// Terminate the child process
     pub fn kill(&mut self) -> Result<(), Box<dyn std::error::Error>> {
@@ -136,17 +709,333 @@ impl PtySession {
 
 impl PtyReader {
     /// Read data from the PTY
+    ///
+    /// Returns 0 once cancelled through [`PtyReader::canceller`].
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Box<dyn std::error::Error>> {
-        let n = self.reader.read(buf)?;
-        Ok(n)
+        #[cfg(unix)]
+        {
+            if !self.interrupt.wait_readable()? {
+                return Ok(0);
+            }
+            match self.reader.read(buf) {
+                // EIO means the slave side has been closed, i.e. end of output
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+                result => Ok(result?),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let n = self.reader.read(buf)?;
+            Ok(n)
+        }
+    }
+
+    /// Handle used to interrupt reads from another thread
+    pub fn canceller(&self) -> ReadCanceller {
+        self.canceller.clone()
+    }
+}
+
+#[cfg(unix)]
+mod unix_interrupt {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Duplicate the master fd so the reader owns a descriptor it can wait on
+    pub fn dup_fd(fd: RawFd) -> io::Result<File> {
+        let dup = check(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) })?;
+        Ok(File::from(unsafe { OwnedFd::from_raw_fd(dup) }))
+    }
+
+    /// Read side of the cancel pipe plus the PTY fd waited on
+    pub struct ReadInterrupt {
+        cancel_rx: OwnedFd,
+        /// Descriptor owned by the reader this interrupt belongs to
+        pty_fd: RawFd,
+    }
+
+    /// Write side of the cancel pipe
+    pub struct Canceller {
+        cancel_tx: OwnedFd,
+    }
+
+    impl ReadInterrupt {
+        pub fn new(pty_fd: RawFd) -> io::Result<(Self, Canceller)> {
+            let mut fds = [0 as libc::c_int; 2];
+            check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+            let (rx, tx) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            for fd in [rx.as_raw_fd(), tx.as_raw_fd()] {
+                check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+            }
+            // Cancelling must never block the caller
+            check(unsafe { libc::fcntl(tx.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) })?;
+            Ok((Self { cancel_rx: rx, pty_fd }, Canceller { cancel_tx: tx }))
+        }
+
+        /// Block until the PTY is readable (true) or the read was cancelled (false)
+        ///
+        /// Uses select() because poll() does not support tty devices on macOS.
+        pub fn wait_readable(&self) -> io::Result<bool> {
+            let pty_fd = self.pty_fd;
+            let cancel_fd = self.cancel_rx.as_raw_fd();
+            if pty_fd.max(cancel_fd) >= libc::FD_SETSIZE as RawFd {
+                // Descriptor out of select() range: fall back to a plain blocking read
+                return Ok(true);
+            }
+
+            loop {
+                let mut set: libc::fd_set = unsafe { std::mem::zeroed() };
+                unsafe {
+                    libc::FD_ZERO(&mut set);
+                    libc::FD_SET(pty_fd, &mut set);
+                    libc::FD_SET(cancel_fd, &mut set);
+                }
+                let ret = unsafe {
+                    libc::select(
+                        pty_fd.max(cancel_fd) + 1,
+                        &mut set,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                    )
+                };
+                if let Err(e) = check(ret) {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                if unsafe { libc::FD_ISSET(cancel_fd, &set) } {
+                    return Ok(false);
+                }
+                if unsafe { libc::FD_ISSET(pty_fd, &set) } {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    impl Canceller {
+        pub fn cancel(&self) {
+            // The byte is never drained, so every later wait sees the pipe readable too
+            let byte = 1u8;
+            unsafe {
+                libc::write(self.cancel_tx.as_raw_fd(), &byte as *const u8 as *const libc::c_void, 1);
+            }
+        }
+    }
+}
+
+/// Reader for the stderr pipe of a session spawned with separate stderr
+///
+/// The FIFO is read through the same select()-based interrupt as the PTY. A write end
+/// is held open here so the read side never sees end of file when the child closes it;
+/// reading ends once cancelled, after the data still buffered has been returned.
+pub struct StderrReader {
+    #[cfg(unix)]
+    fifo: std::fs::File,
+    #[cfg(unix)]
+    _keepalive: std::fs::File,
+    #[cfg(unix)]
+    interrupt: unix_interrupt::ReadInterrupt,
+    canceller: ReadCanceller,
+    #[cfg(unix)]
+    path: std::path::PathBuf,
+    /// Bytes still returned after cancelling, so a process that keeps writing cannot stall the end
+    #[cfg(unix)]
+    drain_budget: usize,
+}
+
+/// Stderr output still delivered after a [`StderrReader`] is cancelled
+#[cfg(unix)]
+const STDERR_DRAIN_BUDGET: usize = 256 * 1024;
+
+#[cfg(unix)]
+impl StderrReader {
+    fn create() -> Result<Self, Box<dyn std::error::Error>> {
+        use std::ffi::CString;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = std::env::temp_dir().join(format!("termy-stderr-{}", uuid::Uuid::new_v4()));
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Nonblocking so opening does not wait for a writer and drained reads cannot hang
+        let open = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+        };
+        let fifo = match open() {
+            Ok(fifo) => fifo,
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(e.into());
+            }
+        };
+        let keepalive = std::fs::OpenOptions::new().write(true).open(&path)?;
+        let (interrupt, canceller) = unix_interrupt::ReadInterrupt::new(fifo.as_raw_fd())?;
+        Ok(Self {
+            fifo,
+            _keepalive: keepalive,
+            interrupt,
+            canceller: ReadCanceller { inner: Arc::new(canceller) },
+            path,
+            drain_budget: STDERR_DRAIN_BUDGET,
+        })
+    }
+}
+
+impl StderrReader {
+    /// Read stderr output; returns 0 once cancelled and drained
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Box<dyn std::error::Error>> {
+        #[cfg(unix)]
+        loop {
+            let cancelled = !self.interrupt.wait_readable()?;
+            if cancelled && self.drain_budget == 0 {
+                return Ok(0);
+            }
+            match self.fifo.read(buf) {
+                Ok(n) => {
+                    if cancelled {
+                        self.drain_budget = self.drain_budget.saturating_sub(n);
+                    }
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if cancelled {
+                        return Ok(0);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = buf;
+            Ok(0)
+        }
+    }
+
+    /// Handle used to end reading from another thread
+    pub fn canceller(&self) -> ReadCanceller {
+        self.canceller.clone()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for StderrReader {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
     }
 }
 
 impl PtyWriter {
-    /// Write data to the PTY
-    pub fn write(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        self.writer.write_all(data)?;
-        self.writer.flush()?;
-        Ok(())
+    /// Start the writer thread for `writer`
+    ///
+    /// The thread ends once the PtyWriter is dropped and the queue is written; a write
+    /// the shell never takes keeps it until the PTY closes.
+    fn new(mut writer: Box<dyn Write + Send>) -> std::io::Result<Self> {
+        let (queue, jobs) = std::sync::mpsc::sync_channel::<WriteJob>(WRITE_QUEUE_LEN);
+        std::thread::Builder::new().name("pty-writer".to_string()).spawn(move || {
+            for job in jobs {
+                let result = writer.write_all(&job.data).and_then(|()| writer.flush());
+                let _ = job.done.send(result);
+            }
+        })?;
+        Ok(Self {
+            queue,
+            pending: Vec::new(),
+            writes: 0,
+            timeout: DEFAULT_WRITE_TIMEOUT,
+        })
+    }
+
+    /// How long [`PendingWrite::wait`] waits for the shell to take the input
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Write data to the PTY, after any coalesced input
+    pub fn write(&mut self, data: &[u8]) -> PendingWrite {
+        // A failed flush shows up again for the data behind it
+        let _ = self.flush_pending();
+        self.write_now(data)
+    }
+
+    /// Hold data back for the next [`flush_pending`](Self::flush_pending)
+    ///
+    /// Returns true if nothing was pending before, i.e. a flush needs to be scheduled.
+    pub fn buffer(&mut self, data: &[u8]) -> bool {
+        let first = self.pending.is_empty();
+        self.pending.extend_from_slice(data);
+        first
+    }
+
+    /// Write coalesced input in one call
+    pub fn flush_pending(&mut self) -> PendingWrite {
+        if self.pending.is_empty() {
+            return self.finished(Ok(None));
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.write_now(&pending)
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Write calls made to the PTY so far
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    fn write_now(&mut self, data: &[u8]) -> PendingWrite {
+        self.writes += 1;
+        let (done, result) = tokio::sync::oneshot::channel();
+        let queued = self.queue.try_send(WriteJob { data: data.to_vec(), done }).map_err(|e| match e {
+            // Everything queued before is still waiting for the shell
+            std::sync::mpsc::TrySendError::Full(_) => WriteError::Timeout,
+            std::sync::mpsc::TrySendError::Disconnected(_) => WriteError::Closed,
+        });
+        self.finished(queued.map(|()| Some(result)))
+    }
+
+    fn finished(
+        &self,
+        done: Result<Option<tokio::sync::oneshot::Receiver<std::io::Result<()>>>, WriteError>,
+    ) -> PendingWrite {
+        PendingWrite {
+            done,
+            deadline: tokio::time::Instant::now() + self.timeout,
+        }
+    }
+}
+
+impl PendingWrite {
+    /// Wait until the data is written, or the write timeout has passed
+    pub async fn wait(self) -> Result<(), WriteError> {
+        let Some(result) = self.done? else {
+            return Ok(());
+        };
+        match tokio::time::timeout_at(self.deadline, result).await {
+            Ok(Ok(written)) => Ok(written?),
+            Ok(Err(_)) => Err(WriteError::Closed),
+            Err(_) => Err(WriteError::Timeout),
+        }
     }
 }
@@ -0,0 +1,600 @@
+// Session sharing
+// Handing a session to another connection (transfer), keeping it without one
+// (detach/reattach) and read-only watchers. Each needs the session's resume_token.
+
+use super::*;
+
+/// Random opaque token that authorizes handing a session over or watching it
+pub(super) fn new_resume_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Compare two tokens in constant time, so timing reveals nothing about the expected one
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    if given.len() != expected.len() {
+        return false;
+    }
+    given.iter().zip(expected).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl SessionState {
+    pub(super) fn resume_token(&self) -> String {
+        self.resume_token.lock().map(|token| token.clone()).unwrap_or_default()
+    }
+
+    /// Replace the resume_token, so the previous one stops working
+    pub(super) fn rotate_resume_token(&self) -> String {
+        let token = new_resume_token();
+        if let Ok(mut current) = self.resume_token.lock() {
+            *current = token.clone();
+        }
+        token
+    }
+
+    /// Fail with [`UNAUTHORIZED`] unless `given` is the session's resume_token
+    pub(super) fn check_resume_token(&self, session_id: &str, given: Option<&str>) -> Result<(), RouterError> {
+        let matches = self
+            .resume_token
+            .lock()
+            .is_ok_and(|expected| given.is_some_and(|token| tokens_match(token, &expected)));
+        if !matches {
+            return Err(RouterError::coded(
+                UNAUTHORIZED,
+                format!("resume_token 缺失或不正确: {}", session_id),
+                serde_json::json!({ "session_id": session_id }),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl PtyHandler {
+    /// Handle the transfer message: hand one of this connection's sessions to another connection
+    ///
+    /// The target connection receives a session_transferred event and from then on gets the
+    /// output and may control the session; this connection loses access to it.
+    ///
+    /// The session's `resume_token`, not its id, is the secret: session ids show up in
+    /// frames, lists and logs, so a transfer must present the token. The event gives the
+    /// new owner a fresh token and the old one stops working.
+    pub(super) async fn handle_transfer(
+        &self,
+        session_id: &str,
+        to_client_id: &str,
+        resume_token: Option<&str>,
+    ) -> Result<Option<ServerResponse>, RouterError> {
+        if to_client_id == self.client_id {
+            return Err(RouterError::InvalidMessage("会话已属于当前连接".to_string()));
+        }
+        let target = self.directory.client(to_client_id)
+            .ok_or_else(|| RouterError::InvalidMessage(format!("未知的客户端: {}", to_client_id)))?;
+        let target_sender = target.owner.sender.lock().await.clone()
+            .ok_or_else(|| RouterError::ModuleError("目标连接没有 WebSocket sender".to_string()))?;
+        let context = {
+            let mut sessions = self.sessions.lock().await;
+            let context = sessions.get(session_id)
+                .ok_or_else(|| self.not_owned(session_id))?;
+            context.state.check_resume_token(session_id, resume_token)?;
+            sessions.remove(session_id).ok_or_else(|| session_not_found(session_id))?
+        };
+        // The target's map stays locked from the limit check to the insert; a refused
+        // session goes back to this connection unchanged
+        let mut target_sessions = target.sessions.lock().await;
+        if let Err(error) = self.check_connection_limit(target_sessions.len()) {
+            drop(target_sessions);
+            self.sessions.lock().await.insert(session_id.to_string(), context);
+            return Err(error);
+        }
+        let resume_token = context.state.rotate_resume_token();
+
+        // Output and counters follow the session; the new owner stops being a watcher
+        if let Ok(mut owner) = context.state.owner.lock() {
+            *owner = target.owner.clone();
+        }
+        context.state.remove_watcher(&target_sender);
+        self.metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+        target.owner.metrics.active_sessions.fetch_add(1, Ordering::Relaxed);
+        target_sessions.insert(session_id.to_string(), context);
+        drop(target_sessions);
+        log_info!("会话已转移: session_id={}, to={}", session_id, to_client_id);
+
+        let event = ServerResponse::new(
+            ModuleType::Pty,
+            "session_transferred",
+            serde_json::json!({
+                "session_id": session_id,
+                "from_client_id": self.client_id,
+                "resume_token": resume_token,
+            }),
+        );
+        send_to(&target_sender, session_id, "转移事件", Message::Text(event.to_json().into())).await;
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "transfer_complete",
+            serde_json::json!({
+                "session_id": session_id,
+                "client_id": to_client_id,
+            }),
+        )))
+    }
+
+    /// Move a session out of this connection, leaving it running without an owner
+    pub(super) fn detach_context(&self, session_id: &str, context: PtySessionContext) {
+        if let Ok(mut owner) = context.state.owner.lock() {
+            *owner = Owner::detached();
+        }
+        self.metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+        self.directory.detach(session_id, context);
+        log_info!("会话已分离: session_id={}", session_id);
+    }
+
+    /// Handle the detach message: keep the session running without this connection
+    ///
+    /// The shell and the read task keep going; output is kept in the scrollback (and sent
+    /// to watchers) until a connection reattaches with the session's resume_token.
+    /// Lifetime limits keep applying. destroy with the resume_token ends it without a
+    /// reattach.
+    pub(super) async fn handle_detach(&self, session_id: &str) -> Result<Option<ServerResponse>, RouterError> {
+        let context = self.sessions.lock().await.remove(session_id)
+            .ok_or_else(|| self.not_owned(session_id))?;
+        let resume_token = context.state.resume_token();
+        self.detach_context(session_id, context);
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "detach_complete",
+            serde_json::json!({
+                "session_id": session_id,
+                "resume_token": resume_token,
+            }),
+        )))
+    }
+
+    /// Handle the reattach message: take over a detached session
+    ///
+    /// The scrollback is replayed as one output frame before live output resumes, and
+    /// the resume_token is rotated as on transfer.
+    pub(super) async fn handle_reattach(
+        &self,
+        session_id: &str,
+        resume_token: Option<&str>,
+    ) -> Result<Option<ServerResponse>, RouterError> {
+        let sender = self.ws_sender.lock().await.clone()
+            .ok_or_else(|| RouterError::ModuleError("WebSocket sender not set".to_string()))?;
+        self.check_connection_limit(self.sessions.lock().await.len())?;
+        let context = self.directory.take_detached(session_id, resume_token)?;
+        let resume_token = context.state.rotate_resume_token();
+        let state = Arc::clone(&context.state);
+        state.remove_watcher(&sender);
+
+        // Hold the socket so live output sent to the new owner queues behind the replay
+        let mut socket = sender.lock().await;
+        let replay = match state.scrollback.lock() {
+            Ok(scrollback) => {
+                if let Ok(mut owner) = state.owner.lock() {
+                    *owner = self.owner();
+                }
+                scrollback.contents()
+            }
+            Err(_) => Vec::new(),
+        };
+        if !replay.is_empty() {
+            if let Err(e) = sink::deliver(&mut *socket, state.output_frame(session_id, &replay)).await {
+                log_error!("发送回放输出失败: session_id={}, {}", session_id, e);
+            }
+        }
+        drop(socket);
+
+        let response = ServerResponse::new(
+            ModuleType::Pty,
+            "reattach_complete",
+            serde_json::json!({
+                "session_id": session_id,
+                "resume_token": resume_token,
+                "replayed_bytes": replay.len(),
+                "exited": state.has_exited(),
+            }),
+        );
+        self.metrics.active_sessions.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().await.insert(session_id.to_string(), context);
+        log_info!("会话已重新连接: session_id={}, 回放 {} 字节", session_id, replay.len());
+
+        Ok(Some(response))
+    }
+
+    /// Handle the watch message: receive a session's output read-only
+    ///
+    /// The session may belong to any connection; as with transfer and reattach, the
+    /// session's `resume_token` must be presented, the owner handing it out grants access.
+    /// Watchers get output produced from now on (no replay) and the exit event; input from
+    /// the watching connection is rejected with [`SESSION_READ_ONLY`]. When the owning
+    /// connection closes, the session ends as usual and watchers see its exit event.
+    pub(super) async fn handle_watch(
+        &self,
+        session_id: &str,
+        resume_token: Option<&str>,
+    ) -> Result<Option<ServerResponse>, RouterError> {
+        if self.sessions.lock().await.contains_key(session_id) {
+            return Err(RouterError::InvalidMessage(format!("会话属于当前连接，无需观察: {}", session_id)));
+        }
+        let state = self.directory.get(session_id)
+            .filter(|state| !state.has_exited())
+            .ok_or_else(|| session_not_found(session_id))?;
+        state.check_resume_token(session_id, resume_token)?;
+        let sender = self.ws_sender.lock().await.clone()
+            .ok_or_else(|| RouterError::ModuleError("WebSocket sender not set".to_string()))?;
+
+        if let Ok(mut watchers) = state.watchers.lock() {
+            if !watchers.iter().any(|watcher| Arc::ptr_eq(watcher, &sender)) {
+                watchers.push(sender);
+            }
+        }
+        if let Ok(mut watching) = self.watching.lock() {
+            if !watching.iter().any(|id| id == session_id) {
+                watching.push(session_id.to_string());
+            }
+        }
+        log_info!("开始观察会话: session_id={}", session_id);
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "watch_started",
+            serde_json::json!({ "session_id": session_id }),
+        )))
+    }
+
+    /// Handle the unwatch message: stop receiving a watched session's output
+    pub(super) async fn handle_unwatch(&self, session_id: &str) -> Result<Option<ServerResponse>, RouterError> {
+        let was_watching = self
+            .watching
+            .lock()
+            .map(|mut watching| {
+                let before = watching.len();
+                watching.retain(|id| id != session_id);
+                watching.len() != before
+            })
+            .unwrap_or(false);
+        if !was_watching {
+            return Err(session_not_found(session_id));
+        }
+
+        if let (Some(state), Some(sender)) = (self.directory.get(session_id), self.ws_sender.lock().await.clone()) {
+            state.remove_watcher(&sender);
+        }
+        log_info!("停止观察会话: session_id={}", session_id);
+
+        Ok(Some(ServerResponse::new(
+            ModuleType::Pty,
+            "watch_stopped",
+            serde_json::json!({ "session_id": session_id }),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::pty::tests::*;
+    #[cfg(unix)]
+    use futures_util::StreamExt;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_other_connections_cannot_control_a_session() {
+        let (owner, _owner_client, other, _other_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+
+        assert!(matches!(
+            other.write_data(&session_id, b"exit\r").await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        assert!(matches!(
+            other.handle(&resize_message(&session_id, 100, 30)).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        assert!(matches!(
+            other.handle_destroy(&session_id).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        // Taking a session is not possible either: only its owner can transfer it
+        let token = resume_token(&owner, &session_id).await;
+        assert!(matches!(
+            other.handle(&transfer_message(&session_id, other.client_id(), &token)).await,
+            Err(RouterError::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            other.handle(&transfer_message(&session_id, owner.client_id(), &token)).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        assert!(owner.is_alive(&session_id).await);
+
+        owner.cleanup_all().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_transfer_hands_session_to_another_connection() {
+        let (owner, _owner_client, target, mut target_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+
+        let token = resume_token(&owner, &session_id).await;
+        let response = owner
+            .handle(&transfer_message(&session_id, target.client_id(), &token))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.msg_type, "transfer_complete");
+        let event = next_event(&mut target_client, "session_transferred", &mut Vec::new()).await;
+        assert_eq!(event["session_id"], session_id.as_str());
+        assert_eq!(event["from_client_id"], owner.client_id());
+
+        // The new owner controls the session and receives its output
+        target.write_data(&session_id, b"printf 'T%sX' 1\r").await.unwrap();
+        read_output_until(&mut target_client, b"T1X").await;
+        assert!(matches!(
+            owner.write_data(&session_id, b"x").await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        assert_eq!(owner.metrics.active_sessions.load(Ordering::Relaxed), 0);
+        assert_eq!(target.metrics.active_sessions.load(Ordering::Relaxed), 1);
+
+        // The old owner closing no longer ends it
+        owner.cleanup_all().await;
+        assert!(target.is_alive(&session_id).await);
+        target.handle_destroy(&session_id).await.unwrap();
+        next_event(&mut target_client, "exit", &mut Vec::new()).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_detach_keeps_session_for_reattach() {
+        let (owner, _owner_client, other, mut other_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+
+        // Output produced while detached is kept for the replay
+        owner.write_data(&session_id, b"sleep 0.3; printf 'D%sT' 1\r").await.unwrap();
+        let response = owner.handle(&watch_message("detach", &session_id)).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "detach_complete");
+        let token = response.payload["resume_token"].as_str().unwrap().to_string();
+        assert_eq!(owner.metrics.active_sessions.load(Ordering::Relaxed), 0);
+        assert!(matches!(
+            owner.write_data(&session_id, b"x").await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        time::sleep(Duration::from_millis(600)).await;
+        assert!(!owner.directory.get(&session_id).unwrap().has_exited());
+
+        let mut reattach = watch_message("reattach", &session_id);
+        assert!(matches!(
+            other.handle(&reattach).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        reattach.payload["resume_token"] = serde_json::json!(token);
+        let response = other.handle(&reattach).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "reattach_complete");
+        assert_eq!(response.payload["exited"], false);
+        assert_ne!(response.payload["resume_token"], token.as_str());
+        read_output_until(&mut other_client, b"D1T").await;
+
+        // Live output resumes on the new connection
+        other.write_data(&session_id, b"printf 'R%sE' 1\r").await.unwrap();
+        read_output_until(&mut other_client, b"R1E").await;
+        assert_eq!(other.metrics.active_sessions.load(Ordering::Relaxed), 1);
+        // Once reattached it can no longer be taken with the old token
+        assert!(matches!(other.handle(&reattach).await, Err(RouterError::Coded { code: SESSION_NOT_FOUND, .. })));
+
+        other.handle_destroy(&session_id).await.unwrap();
+        next_event(&mut other_client, "exit", &mut Vec::new()).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_detach_on_close_outlives_connection() {
+        let (owner, _owner_client, other, _other_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({ "detach_on_close": true })).await;
+        let token = resume_token(&owner, &session_id).await;
+
+        owner.cleanup_all().await;
+        let state = owner.directory.get(&session_id).unwrap();
+        assert!(!state.has_exited());
+
+        // destroy with the resume_token ends a detached session
+        let mut destroy = watch_message("destroy", &session_id);
+        assert!(matches!(
+            other.handle(&destroy).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        destroy.payload["resume_token"] = serde_json::json!(token);
+        let response = other.handle(&destroy).await.unwrap().unwrap();
+        assert_eq!(response.payload["already_destroyed"], false);
+        assert!(other.directory.get(&session_id).is_none());
+        assert_eq!(other.metrics.active_sessions.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connection_at_its_limit_cannot_take_sessions() {
+        let config = PtyConfig { max_sessions_per_connection: Some(1), ..PtyConfig::default() };
+        let (owner, _owner_client, full, _full_client) = connections_with_config(config).await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+        init_session(&full, serde_json::json!({})).await;
+
+        let token = resume_token(&owner, &session_id).await;
+        assert!(matches!(
+            owner.handle(&transfer_message(&session_id, full.client_id(), &token)).await,
+            Err(RouterError::Coded { code: PER_CONNECTION_LIMIT, .. })
+        ));
+        // The refused session stays with its owner, token unchanged
+        assert_eq!(resume_token(&owner, &session_id).await, token);
+        owner.write_data(&session_id, b"true\r").await.unwrap();
+        assert_eq!(full.sessions.lock().await.len(), 1);
+
+        let response = owner.handle(&watch_message("detach", &session_id)).await.unwrap().unwrap();
+        let mut reattach = watch_message("reattach", &session_id);
+        reattach.payload["resume_token"] = response.payload["resume_token"].clone();
+        assert!(matches!(
+            full.handle(&reattach).await,
+            Err(RouterError::Coded { code: PER_CONNECTION_LIMIT, .. })
+        ));
+        // Still detached, so a connection with room takes it
+        assert_eq!(full.sessions.lock().await.len(), 1);
+        let response = owner.handle(&reattach).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "reattach_complete");
+
+        owner.cleanup_all().await;
+        full.cleanup_all().await;
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        let token = new_resume_token();
+        assert_eq!(token.len(), 64);
+        assert!(tokens_match(&token, &token.clone()));
+        assert!(!tokens_match(&token, &new_resume_token()));
+        assert!(!tokens_match(&token[..63], &token));
+        assert!(!tokens_match("", &token));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_transfer_requires_and_rotates_resume_token() {
+        let (owner, _owner_client, target, mut target_client) = owner_and_watcher().await;
+        let response = owner
+            .handle(&message(serde_json::json!({
+                "module": "pty",
+                "type": "init",
+                "shell_type": "custom:/bin/sh",
+            })))
+            .await
+            .unwrap()
+            .unwrap();
+        let session_id = response.payload["session_id"].as_str().unwrap().to_string();
+        let token = response.payload["resume_token"].as_str().unwrap().to_string();
+
+        let mut without_token = transfer_message(&session_id, target.client_id(), "");
+        without_token.payload.as_object_mut().unwrap().remove("resume_token");
+        for msg in [without_token, transfer_message(&session_id, target.client_id(), "wrong")] {
+            assert!(matches!(
+                owner.handle(&msg).await,
+                Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+            ));
+        }
+        // A rejected transfer leaves the session with its owner
+        owner.write_data(&session_id, b"true\r").await.unwrap();
+
+        owner.handle(&transfer_message(&session_id, target.client_id(), &token)).await.unwrap();
+        let event = next_event(&mut target_client, "session_transferred", &mut Vec::new()).await;
+        let rotated = event["resume_token"].as_str().unwrap().to_string();
+        assert_ne!(rotated, token);
+
+        // Only the rotated token hands it on again
+        assert!(matches!(
+            target.handle(&transfer_message(&session_id, owner.client_id(), &token)).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        let response = target
+            .handle(&transfer_message(&session_id, owner.client_id(), &rotated))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.msg_type, "transfer_complete");
+
+        owner.cleanup_all().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watcher_receives_output_read_only() {
+        let (owner, mut owner_client, watcher, mut watcher_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+
+        let response = watcher.handle(&watch_request(&owner, &session_id).await).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "watch_started");
+
+        owner.write_data(&session_id, b"printf 'W%sA' 1\r").await.unwrap();
+        read_output_until(&mut owner_client, b"W1A").await;
+        read_output_until(&mut watcher_client, b"W1A").await;
+
+        // Input, resize and destroy from the watching connection are rejected
+        assert!(matches!(
+            watcher.write_data(&session_id, b"exit\r").await,
+            Err(RouterError::Coded { code: SESSION_READ_ONLY, .. })
+        ));
+        assert!(matches!(
+            watcher.handle(&resize_message(&session_id, 100, 30)).await,
+            Err(RouterError::Coded { code: SESSION_READ_ONLY, .. })
+        ));
+        assert!(matches!(
+            watcher.handle_destroy(&session_id).await,
+            Err(RouterError::Coded { code: SESSION_READ_ONLY, .. })
+        ));
+
+        // The owner closing ends the session for the watcher too
+        owner.cleanup_all().await;
+        next_event(&mut watcher_client, "exit", &mut Vec::new()).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watcher_disconnect_does_not_affect_owner() {
+        let (owner, mut owner_client, watcher, watcher_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+        watcher.handle(&watch_request(&owner, &session_id).await).await.unwrap();
+
+        drop(watcher_client);
+        for round in 0..3 {
+            let command = format!("printf 'R%sD' {}\r", round);
+            owner.write_data(&session_id, command.as_bytes()).await.unwrap();
+            read_output_until(&mut owner_client, format!("R{}D", round).as_bytes()).await;
+        }
+
+        let state = owner.directory.get(&session_id).unwrap();
+        assert!(state.watchers.lock().unwrap().is_empty());
+        owner.cleanup_all().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unwatch_and_watch_errors() {
+        let (owner, _owner_client, watcher, mut watcher_client) = owner_and_watcher().await;
+        let session_id = init_session(&owner, serde_json::json!({})).await;
+
+        assert!(matches!(
+            watcher.handle(&watch_message("watch", "missing")).await,
+            Err(RouterError::Coded { code: SESSION_NOT_FOUND, .. })
+        ));
+        assert!(matches!(
+            owner.handle(&watch_message("watch", &session_id)).await,
+            Err(RouterError::InvalidMessage(_))
+        ));
+        // The id alone does not grant access, as with reattach
+        assert!(matches!(
+            watcher.handle(&watch_message("watch", &session_id)).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+        let mut wrong_token = watch_message("watch", &session_id);
+        wrong_token.payload["resume_token"] = serde_json::json!(new_resume_token());
+        assert!(matches!(
+            watcher.handle(&wrong_token).await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+
+        watcher.handle(&watch_request(&owner, &session_id).await).await.unwrap();
+        let response = watcher.handle(&watch_message("unwatch", &session_id)).await.unwrap().unwrap();
+        assert_eq!(response.msg_type, "watch_stopped");
+
+        owner.write_data(&session_id, b"printf 'U%sW' 0\r").await.unwrap();
+        let frame = time::timeout(Duration::from_millis(300), watcher_client.next()).await;
+        assert!(frame.is_err(), "unwatched connection still received {:?}", frame);
+        // No longer watching, so the session is just another connection's
+        assert!(matches!(
+            watcher.write_data(&session_id, b"x").await,
+            Err(RouterError::Coded { code: UNAUTHORIZED, .. })
+        ));
+
+        owner.cleanup_all().await;
+    }
+}
@@ -1,8 +1,12 @@
 // Shell detection and configuration
 
 use portable_pty::CommandBuilder;
+use std::collections::HashMap;
 use std::env;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use thiserror::Error;
 use which::which;
 
 /// Intelligently detect the system default shell
@@ -23,27 +27,87 @@ pub fn detect_default_shell() -> String {
     }
 }
 
+/// Which shell a Windows host defaults to when SHELL is not set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WindowsShellPreference {
+    /// The program of Windows Terminal's default profile, then the
+    /// [`WindowsPowerShell`](Self::WindowsPowerShell) order (opt-in)
+    Terminal,
+    /// PowerShell 7, then Windows PowerShell; Windows Terminal's settings are not read
+    Pwsh,
+    /// Windows PowerShell 5.x, then PowerShell 7: the order of earlier versions
+    #[default]
+    WindowsPowerShell,
+}
+
+impl WindowsShellPreference {
+    /// Parse a `--windows-shell` value: `terminal`, `pwsh` or `powershell`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "terminal" => Some(Self::Terminal),
+            "pwsh" => Some(Self::Pwsh),
+            "powershell" => Some(Self::WindowsPowerShell),
+            _ => None,
+        }
+    }
+}
+
+/// Set by the server at startup; [`WindowsShellPreference::default`] until then
+static WINDOWS_SHELL_PREFERENCE: OnceLock<WindowsShellPreference> = OnceLock::new();
+
+/// Choose how the default shell is picked on Windows; only the first call takes effect
+pub fn set_windows_shell_preference(preference: WindowsShellPreference) {
+    let _ = WINDOWS_SHELL_PREFERENCE.set(preference);
+}
+
 #[cfg(windows)]
 fn detect_windows_shell() -> String {
-    // 1. Check the SHELL environment variable (user override, such as a Git Bash setup)
-    if let Ok(shell) = env::var("SHELL") {
+    let preference = WINDOWS_SHELL_PREFERENCE.get().copied().unwrap_or_default();
+    pick_windows_shell(
+        preference,
+        |name| env::var(name).ok(),
+        |program| which(program).ok(),
+        || {
+            let program = super::windows_terminal::default_profile_program()?;
+            let program = expand_path(&program, None, None);
+            is_launchable(&program).then(|| program_path(&program))
+        },
+    )
+}
+
+/// Default Windows shell from the environment, a PATH lookup and Windows Terminal's
+/// default profile program (only asked for with [`WindowsShellPreference::Terminal`])
+#[cfg(windows)]
+fn pick_windows_shell(
+    preference: WindowsShellPreference,
+    var: impl Fn(&str) -> Option<String>,
+    find: impl Fn(&str) -> Option<PathBuf>,
+    terminal_default: impl FnOnce() -> Option<String>,
+) -> String {
+    // 1. The SHELL environment variable (user override, such as a Git Bash setup)
+    if let Some(shell) = var("SHELL") {
         return shell;
     }
 
-    // 2. Prefer Windows PowerShell 5.x for broader compatibility
-    //    PowerShell 5.x is built into Windows and is available on nearly all Windows systems
-    if let Ok(path) = which("powershell") {
-        return path.to_string_lossy().into_owned();
+    // 2. The shell a new Windows Terminal tab opens
+    if preference == WindowsShellPreference::Terminal {
+        if let Some(program) = terminal_default() {
+            return program;
+        }
     }
 
-    // 3. If PowerShell 5.x is unavailable, try PowerShell Core (pwsh)
-    //    PowerShell 7+ requires a separate installation and is not present on all systems
-    if let Ok(path) = which("pwsh") {
+    // 3. PowerShell: 5.x is built into every Windows and comes first for compatibility,
+    //    unless PowerShell 7 is preferred
+    let order = match preference {
+        WindowsShellPreference::Pwsh => ["pwsh", "powershell"],
+        _ => ["powershell", "pwsh"],
+    };
+    if let Some(path) = order.iter().find_map(|program| find(program)) {
         return path.to_string_lossy().into_owned();
     }
 
     // 4. Use the COMSPEC environment variable, which is usually cmd.exe
-    if let Ok(shell) = env::var("COMSPEC") {
+    if let Some(shell) = var("COMSPEC") {
         return shell;
     }
 
@@ -76,26 +140,52 @@ fn detect_unix_shell() -> String {
     "/bin/sh".to_string()
 }
 
+/// A shell command together with what it resolved to
+pub struct ResolvedShell {
+    /// Command to spawn
+    pub command: CommandBuilder,
+    /// Program the command launches (argv[0])
+    pub program: String,
+    /// Shell type actually launched: differs from the requested one after a fallback,
+    /// and is `default` for the platform default shell
+    pub shell_type: &'static str,
+}
+
+impl ResolvedShell {
+    fn new(command: CommandBuilder, shell_type: &'static str) -> Self {
+        let program = command
+            .get_argv()
+            .first()
+            .map(|program| program.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Self { command, program, shell_type }
+    }
+
+    fn default_shell() -> Self {
+        Self::new(get_default_shell(), "default")
+    }
+}
+
 /// Get the shell command for a shell type
-pub fn get_shell_by_type(shell_type: Option<&str>) -> CommandBuilder {
+pub fn get_shell_by_type(shell_type: Option<&str>) -> ResolvedShell {
     match shell_type {
-        Some("cmd") => CommandBuilder::new("cmd.exe"),
+        Some("cmd") => ResolvedShell::new(CommandBuilder::new("cmd.exe"), "cmd"),
         Some("powershell") => {
             #[cfg(windows)]
             {
                 // Explicitly use Windows PowerShell 5.x instead of pwsh
                 if let Ok(path) = which("powershell") {
                     eprintln!("[INFO] [Shell] 使用 PowerShell 5.x: {}", path.display());
-                    CommandBuilder::new(path.to_string_lossy().into_owned())
+                    ResolvedShell::new(CommandBuilder::new(path.to_string_lossy().into_owned()), "powershell")
                 } else {
                     eprintln!("[WARN] [Shell] PowerShell 未在 PATH 中找到，使用默认路径");
-                    CommandBuilder::new("powershell.exe")
+                    ResolvedShell::new(CommandBuilder::new("powershell.exe"), "powershell")
                 }
             }
             #[cfg(not(windows))]
             {
                 // On non-Windows platforms, use the default shell
-                get_default_shell()
+                ResolvedShell::default_shell()
             }
         }
         Some("pwsh") => {
@@ -104,25 +194,25 @@ pub fn get_shell_by_type(shell_type: Option<&str>) -> CommandBuilder {
                 // Explicitly use PowerShell Core (pwsh)
                 if let Ok(path) = which("pwsh") {
                     eprintln!("[INFO] [Shell] 使用 PowerShell 7: {}", path.display());
-                    CommandBuilder::new(path.to_string_lossy().into_owned())
+                    ResolvedShell::new(CommandBuilder::new(path.to_string_lossy().into_owned()), "pwsh")
                 } else {
                     eprintln!("[WARN] [Shell] PowerShell 7 未安装，降级到 PowerShell 5.x");
                     // Fall back to Windows PowerShell
                     if let Ok(path) = which("powershell") {
                         eprintln!("[INFO] [Shell] 使用 PowerShell 5.x: {}", path.display());
-                        CommandBuilder::new(path.to_string_lossy().into_owned())
+                        ResolvedShell::new(CommandBuilder::new(path.to_string_lossy().into_owned()), "powershell")
                     } else {
                         eprintln!("[WARN] [Shell] PowerShell 未在 PATH 中找到，使用默认路径");
-                        CommandBuilder::new("powershell.exe")
+                        ResolvedShell::new(CommandBuilder::new("powershell.exe"), "powershell")
                     }
                 }
             }
             #[cfg(not(windows))]
             {
-                CommandBuilder::new("pwsh")
+                ResolvedShell::new(CommandBuilder::new("pwsh"), "pwsh")
             }
         }
-        Some("wsl") => CommandBuilder::new("wsl.exe"),
+        Some("wsl") => ResolvedShell::new(CommandBuilder::new("wsl.exe"), "wsl"),
         Some("gitbash") => {
             #[cfg(windows)]
             {
@@ -131,38 +221,244 @@ pub fn get_shell_by_type(shell_type: Option<&str>) -> CommandBuilder {
                     let mut cmd = CommandBuilder::new(bash_path);
                     // Add --login so the user's shell configuration is loaded
                     cmd.arg("--login");
-                    cmd
+                    ResolvedShell::new(cmd, "gitbash")
                 } else {
                     // Fall back to the default shell
-                    get_default_shell()
+                    ResolvedShell::default_shell()
                 }
             }
             #[cfg(not(windows))]
             {
                 // On non-Windows platforms, use bash
-                CommandBuilder::new("bash")
+                ResolvedShell::new(CommandBuilder::new("bash"), "bash")
             }
         }
-        Some("bash") => CommandBuilder::new("bash"),
-        Some("zsh") => CommandBuilder::new("zsh"),
-        Some("tmux") => command_from_path_or_candidates(
+        Some("bash") => ResolvedShell::new(CommandBuilder::new("bash"), "bash"),
+        Some("zsh") => ResolvedShell::new(CommandBuilder::new("zsh"), "zsh"),
+        Some("tmux") => ResolvedShell::new(
+            command_from_path_or_candidates(
+                "tmux",
+                &[
+                    "/opt/homebrew/bin/tmux",
+                    "/usr/local/bin/tmux",
+                    "/usr/bin/tmux",
+                    "/bin/tmux",
+                    "C:\\msys64\\usr\\bin\\tmux.exe",
+                    "C:\\Program Files\\Git\\usr\\bin\\tmux.exe",
+                ],
+            ),
             "tmux",
-            &[
-                "/opt/homebrew/bin/tmux",
-                "/usr/local/bin/tmux",
-                "/usr/bin/tmux",
-                "/bin/tmux",
-                "C:\\msys64\\usr\\bin\\tmux.exe",
-                "C:\\Program Files\\Git\\usr\\bin\\tmux.exe",
-            ],
         ),
         Some(custom) if custom.starts_with("custom:") => {
-            // Custom shell path in the format "custom:/path/to/shell"
-            let path = &custom[7..]; // Remove the "custom:" prefix
-            CommandBuilder::new(path)
+            // Custom shell in the format "custom:/path/to/shell [args...]"
+            let spec = &custom[7..]; // Remove the "custom:" prefix
+            let argv = parse_custom_command(spec).unwrap_or_else(|_| vec![spec.to_string()]);
+            let mut cmd = CommandBuilder::new(&argv[0]);
+            cmd.args(&argv[1..]);
+            ResolvedShell::new(cmd, "custom")
+        }
+        _ => ResolvedShell::default_shell(), // None or an unknown type uses the default
+    }
+}
+
+/// Command for a program run in place of a shell (init `command`); `argv` is not empty
+pub fn get_program_command(argv: &[String]) -> ResolvedShell {
+    let mut cmd = CommandBuilder::new(&argv[0]);
+    cmd.args(&argv[1..]);
+    ResolvedShell::new(cmd, "command")
+}
+
+/// Absolute path of a program, looked up on PATH when given by name
+///
+/// A program that cannot be found is returned unchanged.
+pub fn program_path(program: &str) -> String {
+    if program.contains(['/', '\\']) {
+        return program.to_string();
+    }
+    which(program)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| program.to_string())
+}
+
+/// Split the part of a `custom:` shell type after the prefix into program and arguments
+///
+/// A spec that names an existing file is taken whole, so paths containing spaces keep
+/// working unquoted. Otherwise it is split on whitespace; single quotes are literal,
+/// double quotes allow `\"` and `\\` escapes, and backslashes elsewhere are kept as-is
+/// for Windows paths.
+pub fn parse_custom_command(spec: &str) -> Result<Vec<String>, String> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err("custom shell command is empty".to_string());
+    }
+    if Path::new(spec).is_file() {
+        return Ok(vec![spec.to_string()]);
+    }
+    split_command_line(spec)
+}
+
+fn split_command_line(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    // Distinguishes an empty quoted argument ('') from no argument
+    let mut in_arg = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            '\'' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err("unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"') | Some('\\')) => {
+                            current.push(chars.next().unwrap_or('\\'));
+                        }
+                        Some(c) => current.push(c),
+                        None => return Err("unterminated double quote".to_string()),
+                    }
+                }
+            }
+            c => {
+                in_arg = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+
+    if args.is_empty() {
+        return Err("custom shell command is empty".to_string());
+    }
+    Ok(args)
+}
+
+/// Check that a requested shell type can be launched
+///
+/// Only `custom:` commands are checked; named shell types fall back to the default shell.
+pub fn validate_shell_type(shell_type: Option<&str>) -> Result<(), LaunchProblem> {
+    let Some(spec) = shell_type.and_then(|t| t.strip_prefix("custom:")) else {
+        return Ok(());
+    };
+    let argv = parse_custom_command(spec).map_err(LaunchProblem::InvalidShellType)?;
+    if !is_launchable(&argv[0]) {
+        return Err(LaunchProblem::ShellNotFound(argv[0].clone()));
+    }
+    Ok(())
+}
+
+/// Why a shell would fail to launch
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LaunchProblem {
+    #[error("invalid shell type: {0}")]
+    InvalidShellType(String),
+    #[error("shell not found: {0}")]
+    ShellNotFound(String),
+    #[error("shell is not an executable file: {0}")]
+    NotExecutable(String),
+    #[error("working directory does not exist: {0}")]
+    CwdNotFound(String),
+    #[error("working directory is not a directory: {0}")]
+    CwdNotDirectory(String),
+}
+
+impl LaunchProblem {
+    /// Stable identifier of the problem for clients
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LaunchProblem::InvalidShellType(_) => "invalid_shell_type",
+            LaunchProblem::ShellNotFound(_) => "shell_not_found",
+            LaunchProblem::NotExecutable(_) => "not_executable",
+            LaunchProblem::CwdNotFound(_) => "cwd_not_found",
+            LaunchProblem::CwdNotDirectory(_) => "cwd_not_directory",
+        }
+    }
+}
+
+/// Check that a shell type and working directory would launch, without spawning anything
+///
+/// Unlike [`validate_shell_type`] this also resolves named shell types, so a missing
+/// `zsh` is reported rather than silently launched as the default shell's fallback.
+/// Returns the resolved program path.
+pub fn check_launch(shell_type: Option<&str>, cwd: Option<&str>) -> Result<PathBuf, LaunchProblem> {
+    if let Some(spec) = shell_type.and_then(|t| t.strip_prefix("custom:")) {
+        parse_custom_command(spec).map_err(LaunchProblem::InvalidShellType)?;
+    }
+    let program = get_shell_by_type(shell_type).program;
+
+    let resolved = if program.contains(['/', '\\']) {
+        let path = PathBuf::from(&program);
+        if !path.exists() {
+            return Err(LaunchProblem::ShellNotFound(program));
+        }
+        if !is_executable_file(&path) {
+            return Err(LaunchProblem::NotExecutable(program));
+        }
+        path
+    } else {
+        which(&program).map_err(|_| LaunchProblem::ShellNotFound(program))?
+    };
+
+    if let Some(cwd) = cwd {
+        let path = Path::new(cwd);
+        if !path.exists() {
+            return Err(LaunchProblem::CwdNotFound(cwd.to_string()));
+        }
+        if !path.is_dir() {
+            return Err(LaunchProblem::CwdNotDirectory(cwd.to_string()));
         }
-        _ => get_default_shell(), // None or an unknown type uses the default
     }
+    Ok(resolved)
+}
+
+fn is_executable_file(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+/// Whether the requested shell would run as an interactive session
+///
+/// A shell given nothing to run (no arguments beyond the program), or asked explicitly
+/// with `-i` / `-NoExit`, reads its commands from the terminal.
+pub fn runs_interactively(shell_type: Option<&str>, shell_args: Option<&[String]>) -> bool {
+    let cmd = get_shell_by_type(shell_type).command;
+    let mut args: Vec<String> = cmd
+        .get_argv()
+        .iter()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    args.extend(shell_args.unwrap_or_default().iter().cloned());
+    args.is_empty()
+        || args.iter().any(|arg| {
+            arg == "-i" || arg == "--interactive" || arg.eq_ignore_ascii_case("-NoExit")
+        })
 }
 
 /// Get the default shell command
@@ -214,29 +510,306 @@ fn detect_gitbash() -> Option<String> {
     None
 }
 
-/// Get shell startup arguments for login-shell behavior
-#[allow(dead_code)]
-pub fn get_shell_login_args(shell_path: &str) -> Vec<String> {
-    let shell_name = Path::new(shell_path)
-        .file_name()
-        .and_then(|name| name.to_str())
+/// Shell types that can be requested on this platform
+#[cfg(windows)]
+const SHELL_TYPE_CANDIDATES: &[&str] = &["cmd", "powershell", "pwsh", "wsl", "gitbash", "bash", "zsh", "tmux"];
+
+/// Shell types that can be requested on this platform
+///
+/// `powershell` and `gitbash` are left out because they only alias other shells here.
+#[cfg(not(windows))]
+const SHELL_TYPE_CANDIDATES: &[&str] = &["bash", "zsh", "pwsh", "tmux"];
+
+/// Shell types whose program exists on this host
+///
+/// Probed once per process; installing a shell requires a server restart to show up.
+pub fn available_shell_types() -> &'static [String] {
+    static AVAILABLE: OnceLock<Vec<String>> = OnceLock::new();
+    AVAILABLE.get_or_init(|| probe_shell_types(SHELL_TYPE_CANDIDATES, is_launchable))
+}
+
+fn probe_shell_types(candidates: &[&str], is_launchable: impl Fn(&str) -> bool) -> Vec<String> {
+    candidates
+        .iter()
+        .filter(|shell_type| is_launchable(&get_shell_by_type(Some(shell_type)).program))
+        .map(|shell_type| shell_type.to_string())
+        .collect()
+}
+
+/// Whether a program path or name resolves to an executable
+pub fn is_launchable(program: &str) -> bool {
+    if program.contains(['/', '\\']) {
+        Path::new(program).exists()
+    } else {
+        which(program).is_ok()
+    }
+}
+
+/// Get the lowercase file name of a shell program without the `.exe` suffix
+pub(super) fn shell_name(shell_path: &str) -> String {
+    // Split on both separators so Windows paths are handled on every platform
+    let name = shell_path
+        .rsplit(['/', '\\'])
+        .next()
         .unwrap_or(shell_path)
         .to_lowercase();
+    match name.strip_suffix(".exe") {
+        Some(stem) => stem.to_string(),
+        None => name,
+    }
+}
+
+/// Command syntax family of an interactive shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellSyntax {
+    Posix,
+    Fish,
+    PowerShell,
+    Cmd,
+}
+
+impl ShellSyntax {
+    /// Determine the syntax from the shell program path
+    pub fn from_program(shell_path: &str) -> Self {
+        match shell_name(shell_path).as_str() {
+            "pwsh" | "powershell" => ShellSyntax::PowerShell,
+            "cmd" => ShellSyntax::Cmd,
+            "fish" => ShellSyntax::Fish,
+            _ => ShellSyntax::Posix,
+        }
+    }
+
+    /// Command line that sets an environment variable in the running shell
+    ///
+    /// Values are quoted for the target shell; cmd has no reliable quoting for `%`,
+    /// so `%` sequences in values may still be expanded there.
+    pub fn set_env_command(self, key: &str, value: &str) -> String {
+        match self {
+            ShellSyntax::Posix => format!("export {}='{}'", key, value.replace('\'', "'\\''")),
+            ShellSyntax::Fish => format!(
+                "set -gx {} '{}'",
+                key,
+                value.replace('\\', "\\\\").replace('\'', "\\'")
+            ),
+            ShellSyntax::PowerShell => format!("$env:{} = '{}'", key, value.replace('\'', "''")),
+            ShellSyntax::Cmd => format!("set \"{}={}\"", key, value),
+        }
+    }
+}
+
+/// Whether a name is a portable environment variable name
+pub fn is_valid_env_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Append login and user arguments to a shell command
+///
+/// Login arguments go directly after the program, followed by `shell_args`,
+/// so options such as `-c <command>` that consume the remaining arguments keep working.
+/// Login arguments already present on the command (e.g. `--login` for Git Bash) are not repeated.
+pub fn append_shell_args(cmd: &mut CommandBuilder, shell_args: Option<&[String]>, login: bool) {
+    if login {
+        let program = cmd
+            .get_argv()
+            .first()
+            .map(|program| program.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let has_login_arg = cmd
+            .get_argv()
+            .iter()
+            .skip(1)
+            .any(|arg| arg == "-l" || arg == "--login");
+        if !has_login_arg {
+            for arg in get_shell_login_args(&program) {
+                cmd.arg(arg);
+            }
+        }
+    }
+
+    if let Some(args) = shell_args {
+        for arg in args {
+            cmd.arg(arg);
+        }
+    }
+}
+
+/// Check a WSL distribution or user name that goes on the `wsl.exe` command line
+///
+/// Only letters, digits, `.`, `_` and `-` are accepted (a leading `-` is not), which
+/// covers distribution and Linux user names and rules out quoting and option injection.
+pub fn validate_wsl_name(field: &str, name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("invalid {}: {:?}", field, name))
+    }
+}
+
+/// Select the WSL distribution (`-d`) and user (`-u`); the default distribution and its
+/// default user are used for what is not given
+///
+/// Must be applied before any other argument: `wsl.exe` treats what follows them as the command.
+pub fn append_wsl_args(cmd: &mut CommandBuilder, distro: Option<&str>, user: Option<&str>) {
+    if let Some(distro) = distro {
+        cmd.args(["-d", distro]);
+    }
+    if let Some(user) = user {
+        cmd.args(["-u", user]);
+    }
+}
+
+/// Server environment variables a `clean_env` session keeps
+///
+/// Windows programs fail in odd ways without SystemRoot and friends, so those stay as well.
+#[cfg(not(windows))]
+pub const CLEAN_ENV_ALLOWLIST: &[&str] = &["PATH", "HOME", "USER", "TERM", "LANG"];
+#[cfg(windows)]
+pub const CLEAN_ENV_ALLOWLIST: &[&str] = &[
+    "PATH", "USERPROFILE", "USERNAME", "TERM", "LANG", "SystemRoot", "SystemDrive", "ComSpec",
+    "PATHEXT", "windir", "TEMP", "TMP",
+];
 
-    match shell_name.as_str() {
+/// PATH of a `clean_env` session when the server has none either
+fn default_path() -> String {
+    if cfg!(windows) {
+        let root = env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".to_string());
+        format!("{0}\\system32;{0};{0}\\System32\\WindowsPowerShell\\v1.0", root)
+    } else {
+        "/usr/local/bin:/usr/bin:/bin:/usr/local/sbin:/usr/sbin:/sbin".to_string()
+    }
+}
+
+/// Drop the inherited environment except [`CLEAN_ENV_ALLOWLIST`]
+///
+/// Variables set on the command afterwards (the session's `env`, TERM, locale) still apply.
+pub fn clear_env(cmd: &mut CommandBuilder) {
+    cmd.env_clear();
+    for key in CLEAN_ENV_ALLOWLIST {
+        if let Some(value) = env::var_os(key) {
+            cmd.env(key, value);
+        }
+    }
+    if cmd.get_env("PATH").is_none() {
+        cmd.env("PATH", default_path());
+    }
+}
+
+/// Home directory of the user running the server
+pub fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    env::var_os(var).filter(|home| !home.is_empty()).map(PathBuf::from)
+}
+
+/// Expand a leading `~` and environment variables in a working directory
+///
+/// `$NAME` and `${NAME}` are expanded, and `%NAME%` as well on Windows. Variables come
+/// from the session's `env` before the server's environment; unknown ones are left as
+/// written. `~user` is not supported.
+pub fn expand_path(path: &str, session_env: Option<&HashMap<String, String>>, home: Option<&Path>) -> String {
+    let lookup = |name: &str| {
+        session_env
+            .and_then(|vars| vars.get(name).cloned())
+            .or_else(|| env::var(name).ok())
+    };
+    let markers: &[char] = if cfg!(windows) { &['$', '%'] } else { &['$'] };
+
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if let Some(home) = home {
+        let separators: &[char] = if cfg!(windows) { &['/', '\\'] } else { &['/'] };
+        if let Some(tail) = rest.strip_prefix('~').filter(|tail| tail.is_empty() || tail.starts_with(separators)) {
+            expanded.push_str(&home.to_string_lossy());
+            rest = tail;
+        }
+    }
+    while let Some(start) = rest.find(markers) {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        // Name of the variable and the length of the reference after the marker
+        let (name, len) = if rest[start..].starts_with('$') {
+            match after.strip_prefix('{') {
+                Some(braced) => braced.find('}').map_or(("", 0), |end| (&braced[..end], end + 2)),
+                None => {
+                    let end = after
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(after.len());
+                    (&after[..end], end)
+                }
+            }
+        } else {
+            after.find('%').map_or(("", 0), |end| (&after[..end], end + 1))
+        };
+        match Some(name).filter(|name| !name.is_empty()).and_then(lookup) {
+            Some(value) => expanded.push_str(&value),
+            None => expanded.push_str(&rest[start..start + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Get shell startup arguments for login-shell behavior
+pub fn get_shell_login_args(shell_path: &str) -> Vec<String> {
+    match shell_name(shell_path).as_str() {
         "bash" | "zsh" | "fish" | "sh" => vec!["-l".to_string()],
-        "pwsh" | "pwsh.exe" | "powershell" | "powershell.exe" => {
+        "pwsh" | "powershell" => {
             vec!["-NoLogo".to_string()]
         }
-        "cmd" | "cmd.exe" => vec![],
+        "cmd" => vec![],
         _ => vec![],
     }
 }
 
+/// Get shell startup arguments that skip the user's startup files
+///
+/// cmd gets `/D`, which skips the AutoRun commands from the registry. POSIX `sh` has
+/// no such option; it reads `$ENV`, which the caller can leave unset.
+pub fn get_shell_norc_args(shell_path: &str) -> Vec<String> {
+    let args: &[&str] = match shell_name(shell_path).as_str() {
+        "bash" => &["--norc", "--noprofile"],
+        "zsh" => &["-f"],
+        "fish" => &["--no-config"],
+        "nu" => &["--no-config-file"],
+        "pwsh" | "powershell" => &["-NoProfile"],
+        "cmd" => &["/D"],
+        _ => &[],
+    };
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Append the arguments of [`get_shell_norc_args`] for the command's program
+///
+/// Must come before `shell_args`: bash only accepts its long options before short ones.
+pub fn append_norc_args(cmd: &mut CommandBuilder) {
+    let program = cmd
+        .get_argv()
+        .first()
+        .map(|program| program.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    for arg in get_shell_norc_args(&program) {
+        cmd.arg(arg);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_runs_interactively() {
+        assert!(runs_interactively(Some("custom:/bin/sh"), None));
+        assert!(runs_interactively(Some("custom:/bin/sh"), Some(&["-i".to_string()])));
+        assert!(!runs_interactively(Some("custom:/bin/sh"), Some(&["-c".to_string(), "true".to_string()])));
+        assert!(!runs_interactively(Some("custom:/bin/sh -c true"), None));
+        assert!(runs_interactively(Some("custom:pwsh -NoExit -Command x"), None));
+    }
+
     #[test]
     fn test_get_default_shell() {
         // Only verify that the function returns successfully without checking the exact result
@@ -263,6 +836,83 @@ mod tests {
         assert!(cmd_args.is_empty());
     }
     
+    fn argv(cmd: &CommandBuilder) -> Vec<String> {
+        cmd.get_argv()
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_get_shell_login_args_windows_names() {
+        assert_eq!(get_shell_login_args("C:\\Program Files\\Git\\bin\\bash.exe"), vec!["-l".to_string()]);
+        assert_eq!(get_shell_login_args("PowerShell.exe"), vec!["-NoLogo".to_string()]);
+    }
+
+    #[test]
+    fn test_append_shell_args_login_precedes_user_args() {
+        let mut cmd = get_shell_by_type(Some("custom:/bin/bash")).command;
+        let args = vec!["-c".to_string(), "echo hi".to_string()];
+        append_shell_args(&mut cmd, Some(&args), true);
+        assert_eq!(argv(&cmd), vec!["/bin/bash", "-l", "-c", "echo hi"]);
+    }
+
+    #[test]
+    fn test_append_shell_args_without_login() {
+        let mut cmd = get_shell_by_type(Some("custom:/bin/zsh")).command;
+        let args = vec!["-i".to_string()];
+        append_shell_args(&mut cmd, Some(&args), false);
+        assert_eq!(argv(&cmd), vec!["/bin/zsh", "-i"]);
+    }
+
+    #[test]
+    fn test_append_shell_args_does_not_repeat_login() {
+        let mut cmd = CommandBuilder::new("bash");
+        cmd.arg("--login");
+        append_shell_args(&mut cmd, None, true);
+        assert_eq!(argv(&cmd), vec!["bash", "--login"]);
+    }
+
+    #[test]
+    fn test_append_shell_args_unknown_shell_has_no_login_args() {
+        let mut cmd = get_shell_by_type(Some("custom:/opt/tools/myshell")).command;
+        append_shell_args(&mut cmd, None, true);
+        assert_eq!(argv(&cmd), vec!["/opt/tools/myshell"]);
+    }
+
+    #[test]
+    fn test_get_shell_norc_args() {
+        assert_eq!(get_shell_norc_args("/bin/bash"), vec!["--norc", "--noprofile"]);
+        assert_eq!(get_shell_norc_args("/usr/bin/zsh"), vec!["-f"]);
+        assert_eq!(get_shell_norc_args("/usr/bin/fish"), vec!["--no-config"]);
+        assert_eq!(get_shell_norc_args("/usr/local/bin/nu"), vec!["--no-config-file"]);
+        assert_eq!(get_shell_norc_args("pwsh.exe"), vec!["-NoProfile"]);
+        assert_eq!(get_shell_norc_args("PowerShell.exe"), vec!["-NoProfile"]);
+        assert_eq!(get_shell_norc_args("C:\\Windows\\System32\\cmd.exe"), vec!["/D"]);
+        assert!(get_shell_norc_args("/bin/sh").is_empty());
+        assert!(get_shell_norc_args("/opt/tools/myshell").is_empty());
+    }
+
+    #[test]
+    fn test_append_norc_args_precedes_user_args() {
+        let mut cmd = get_shell_by_type(Some("custom:/bin/bash")).command;
+        append_norc_args(&mut cmd);
+        append_shell_args(&mut cmd, Some(&["-c".to_string(), "echo hi".to_string()]), false);
+        assert_eq!(argv(&cmd), vec!["/bin/bash", "--norc", "--noprofile", "-c", "echo hi"]);
+    }
+
+    #[test]
+    fn test_clear_env_keeps_only_the_allowlist() {
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.env("TERMY_TEST_INHERITED", "1");
+        clear_env(&mut cmd);
+        assert!(cmd.get_env("TERMY_TEST_INHERITED").is_none());
+        assert!(cmd.get_env("PATH").is_some_and(|path| !path.is_empty()));
+        for key in CLEAN_ENV_ALLOWLIST {
+            assert_eq!(cmd.get_env(key).is_some(), env::var_os(key).is_some() || *key == "PATH", "{}", key);
+        }
+    }
+
     #[test]
     fn test_get_shell_by_type_cmd() {
         let _cmd = get_shell_by_type(Some("cmd"));
@@ -310,4 +960,275 @@ mod tests {
         let _cmd = get_shell_by_type(Some("unknown_shell"));
         // An unknown type should return the default shell
     }
+
+    #[test]
+    fn test_get_shell_by_type_reports_resolution() {
+        let custom = get_shell_by_type(Some("custom:/bin/sh -l"));
+        assert_eq!(custom.program, "/bin/sh");
+        assert_eq!(custom.shell_type, "custom");
+
+        let unknown = get_shell_by_type(Some("unknown_shell"));
+        assert_eq!(unknown.shell_type, "default");
+        assert_eq!(unknown.program, detect_default_shell());
+
+        assert_eq!(get_shell_by_type(Some("bash")).shell_type, "bash");
+        #[cfg(not(windows))]
+        assert_eq!(get_shell_by_type(Some("powershell")).shell_type, "default");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_program_path() {
+        assert_eq!(program_path("/opt/tools/myshell"), "/opt/tools/myshell");
+        assert!(program_path("sh").ends_with("/sh"));
+        assert_eq!(program_path("definitely-missing-shell"), "definitely-missing-shell");
+    }
+
+    #[test]
+    fn test_probe_keeps_launchable_shell_types_in_order() {
+        let available = probe_shell_types(&["zsh", "bash", "custom:/opt/missing"], |program| {
+            program != "/opt/missing"
+        });
+        assert_eq!(available, vec!["zsh".to_string(), "bash".to_string()]);
+    }
+
+    #[test]
+    fn test_available_shell_types_is_cached() {
+        let first = available_shell_types();
+        assert!(std::ptr::eq(first, available_shell_types()));
+    }
+
+    #[test]
+    fn test_shell_syntax_from_program() {
+        assert_eq!(ShellSyntax::from_program("/bin/zsh"), ShellSyntax::Posix);
+        assert_eq!(ShellSyntax::from_program("/usr/bin/fish"), ShellSyntax::Fish);
+        assert_eq!(ShellSyntax::from_program("C:\\Program Files\\PowerShell\\7\\pwsh.exe"), ShellSyntax::PowerShell);
+        assert_eq!(ShellSyntax::from_program("cmd.exe"), ShellSyntax::Cmd);
+    }
+
+    #[test]
+    fn test_set_env_command_quotes_values() {
+        assert_eq!(ShellSyntax::Posix.set_env_command("A", "it's"), "export A='it'\\''s'");
+        assert_eq!(ShellSyntax::Fish.set_env_command("A", "it's"), "set -gx A 'it\\'s'");
+        assert_eq!(ShellSyntax::PowerShell.set_env_command("A", "it's"), "$env:A = 'it''s'");
+        assert_eq!(ShellSyntax::Cmd.set_env_command("A", "x y"), "set \"A=x y\"");
+    }
+
+    #[test]
+    fn test_is_valid_env_key() {
+        assert!(is_valid_env_key("PATH"));
+        assert!(is_valid_env_key("_my_var2"));
+        assert!(!is_valid_env_key("2X"));
+        assert!(!is_valid_env_key("A;rm"));
+        assert!(!is_valid_env_key(""));
+    }
+
+    #[test]
+    fn test_parse_custom_command_splits_quoted_args() {
+        assert_eq!(
+            parse_custom_command("/usr/bin/env -- fish --login").unwrap(),
+            vec!["/usr/bin/env", "--", "fish", "--login"]
+        );
+        assert_eq!(
+            parse_custom_command("sh -c 'echo \"a b\"' \"x \\\"y\\\"\" ''").unwrap(),
+            vec!["sh", "-c", "echo \"a b\"", "x \"y\"", ""]
+        );
+        assert_eq!(
+            parse_custom_command("C:\\tools\\bash.exe --login").unwrap(),
+            vec!["C:\\tools\\bash.exe", "--login"]
+        );
+        assert!(parse_custom_command("sh -c 'oops").is_err());
+        assert!(parse_custom_command("   ").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_custom_shell_type_builds_argv() {
+        let cmd = get_shell_by_type(Some("custom:/bin/sh -c 'exit 0'")).command;
+        assert_eq!(argv(&cmd), vec!["/bin/sh", "-c", "exit 0"]);
+        // A bare existing path stays a single program
+        assert_eq!(argv(&get_shell_by_type(Some("custom:/bin/sh")).command), vec!["/bin/sh"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_shell_type() {
+        assert!(validate_shell_type(Some("custom:/bin/sh -l")).is_ok());
+        assert_eq!(
+            validate_shell_type(Some("custom:/definitely/missing/shell")),
+            Err(LaunchProblem::ShellNotFound("/definitely/missing/shell".to_string()))
+        );
+        assert!(validate_shell_type(Some("zsh")).is_ok());
+        assert!(validate_shell_type(None).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_launch() {
+        assert_eq!(check_launch(Some("custom:/bin/sh -l"), Some("/")), Ok(PathBuf::from("/bin/sh")));
+        assert_eq!(
+            check_launch(Some("custom:/definitely/missing/shell"), None).map_err(|e| e.reason()),
+            Err("shell_not_found")
+        );
+        let plain = std::env::temp_dir().join(format!("termy-not-executable-{}", std::process::id()));
+        std::fs::write(&plain, "").unwrap();
+        let shell_type = format!("custom:{}", plain.display());
+        assert_eq!(check_launch(Some(&shell_type), None).map_err(|e| e.reason()), Err("not_executable"));
+        std::fs::remove_file(&plain).unwrap();
+        assert_eq!(
+            check_launch(Some("custom:'unterminated"), None).map_err(|e| e.reason()),
+            Err("invalid_shell_type")
+        );
+        assert_eq!(
+            check_launch(Some("custom:/bin/sh"), Some("/definitely/missing")).map_err(|e| e.reason()),
+            Err("cwd_not_found")
+        );
+        assert_eq!(
+            check_launch(Some("custom:/bin/sh"), Some("/bin/sh")).map_err(|e| e.reason()),
+            Err("cwd_not_directory")
+        );
+        // Named shell types are resolved too instead of falling back
+        assert_eq!(check_launch(Some("zsh"), None).is_ok(), which("zsh").is_ok());
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = Path::new("/home/termy");
+        let vars = HashMap::from([
+            ("PROJECT".to_string(), "notes".to_string()),
+            ("ROOT_DIR".to_string(), "/srv".to_string()),
+        ]);
+        let expand = |path| expand_path(path, Some(&vars), Some(home));
+
+        assert_eq!(expand("~"), "/home/termy");
+        assert_eq!(expand("~/vault"), "/home/termy/vault");
+        assert_eq!(expand("$ROOT_DIR/${PROJECT}/x"), "/srv/notes/x");
+        // Only a leading ~ alone or before a separator refers to the home directory
+        assert_eq!(expand("~other/a~b"), "~other/a~b");
+        // Unknown and malformed references stay as written
+        assert_eq!(expand("/a/$TERMY_NO_SUCH_VAR/${PROJECT"), "/a/$TERMY_NO_SUCH_VAR/${PROJECT");
+        assert_eq!(expand("/cost/$5/$"), "/cost/$5/$");
+        assert_eq!(expand_path("~/vault", None, None), "~/vault");
+    }
+
+    #[test]
+    fn test_validate_wsl_name() {
+        assert!(validate_wsl_name("wsl_distro", "Ubuntu-22.04").is_ok());
+        assert!(validate_wsl_name("wsl_user", "dev_user").is_ok());
+        assert!(validate_wsl_name("wsl_distro", "").is_err());
+        assert!(validate_wsl_name("wsl_distro", "-e").is_err());
+        assert!(validate_wsl_name("wsl_distro", "Ubuntu & calc").is_err());
+        assert!(validate_wsl_name("wsl_user", "root\"x").is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_wsl_distro_and_user_args() {
+        let argv = |distro: Option<&str>, user: Option<&str>| {
+            let mut cmd = get_shell_by_type(Some("wsl")).command;
+            append_wsl_args(&mut cmd, distro, user);
+            append_shell_args(&mut cmd, Some(&["--cd".to_string(), "~".to_string()]), false);
+            cmd.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect::<Vec<_>>()
+        };
+        assert_eq!(argv(None, None), ["wsl.exe", "--cd", "~"]);
+        assert_eq!(argv(Some("Debian"), None), ["wsl.exe", "-d", "Debian", "--cd", "~"]);
+        assert_eq!(argv(Some("Debian"), Some("dev")), ["wsl.exe", "-d", "Debian", "-u", "dev", "--cd", "~"]);
+    }
+
+    /// PATH of a host with the given programs installed, and no SHELL or COMSPEC
+    #[cfg(windows)]
+    fn pick_with(
+        preference: WindowsShellPreference,
+        installed: &[&str],
+        var: impl Fn(&str) -> Option<String>,
+        terminal: Option<&str>,
+    ) -> String {
+        let find = |program: &str| {
+            installed
+                .contains(&program)
+                .then(|| PathBuf::from(format!("C:\\Programs\\{}.exe", program)))
+        };
+        pick_windows_shell(preference, var, find, || terminal.map(str::to_string))
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_shell_prefers_pwsh_unless_configured_otherwise() {
+        let both = ["powershell", "pwsh"];
+        let no_var = |_: &str| None;
+        assert_eq!(pick_with(WindowsShellPreference::Pwsh, &both, no_var, None), "C:\\Programs\\pwsh.exe");
+        assert_eq!(
+            pick_with(WindowsShellPreference::WindowsPowerShell, &both, no_var, None),
+            "C:\\Programs\\powershell.exe"
+        );
+        // Either order falls back to the one that is installed
+        assert_eq!(
+            pick_with(WindowsShellPreference::Pwsh, &["powershell"], no_var, None),
+            "C:\\Programs\\powershell.exe"
+        );
+        assert_eq!(
+            pick_with(WindowsShellPreference::WindowsPowerShell, &["pwsh"], no_var, None),
+            "C:\\Programs\\pwsh.exe"
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_shell_follows_terminal_default_profile() {
+        let both = ["powershell", "pwsh"];
+        let no_var = |_: &str| None;
+        let cmd = "C:\\Windows\\System32\\cmd.exe";
+        let terminal = Some(cmd);
+        assert_eq!(pick_with(WindowsShellPreference::Terminal, &both, no_var, terminal), cmd);
+        // Without a usable profile the order of earlier versions applies
+        assert_eq!(pick_with(WindowsShellPreference::Terminal, &both, no_var, None), "C:\\Programs\\powershell.exe");
+        // Only the terminal preference reads the settings
+        assert_eq!(pick_with(WindowsShellPreference::Pwsh, &both, no_var, terminal), "C:\\Programs\\pwsh.exe");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_shell_default_order_is_unchanged() {
+        // Following Windows Terminal is opt-in; the default never reads its settings
+        let both = ["powershell", "pwsh"];
+        let no_var = |_: &str| None;
+        let preference = WindowsShellPreference::default();
+        assert_eq!(pick_with(preference, &both, no_var, Some("C:\\Programs\\nu.exe")), "C:\\Programs\\powershell.exe");
+        assert_eq!(pick_with(preference, &["pwsh"], no_var, None), "C:\\Programs\\pwsh.exe");
+
+        // Opting in on a host without a settings file keeps the same order
+        let local_app_data = std::env::temp_dir().join(format!("termy-no-terminal-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&local_app_data).unwrap();
+        assert_eq!(super::super::windows_terminal::default_profile_program_in(&local_app_data), None);
+        assert_eq!(pick_with(WindowsShellPreference::Terminal, &both, no_var, None), "C:\\Programs\\powershell.exe");
+        std::fs::remove_dir_all(&local_app_data).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_shell_keeps_shell_and_comspec_precedence() {
+        let var = |name: &str| match name {
+            "SHELL" => Some("C:\\Git\\bin\\bash.exe".to_string()),
+            "COMSPEC" => Some("C:\\Windows\\System32\\cmd.exe".to_string()),
+            _ => None,
+        };
+        let terminal = Some("C:\\Programs\\nu.exe");
+        for preference in [WindowsShellPreference::Terminal, WindowsShellPreference::WindowsPowerShell] {
+            assert_eq!(pick_with(preference, &["pwsh"], var, terminal), "C:\\Git\\bin\\bash.exe");
+        }
+        let comspec_only = |name: &str| var(name).filter(|_| name == "COMSPEC");
+        assert_eq!(
+            pick_with(WindowsShellPreference::Pwsh, &[], comspec_only, None),
+            "C:\\Windows\\System32\\cmd.exe"
+        );
+        assert_eq!(pick_with(WindowsShellPreference::Pwsh, &[], |_| None, None), "cmd.exe");
+    }
+
+    #[test]
+    fn test_parses_windows_shell_preference() {
+        assert_eq!(WindowsShellPreference::parse("terminal"), Some(WindowsShellPreference::Terminal));
+        assert_eq!(WindowsShellPreference::parse("pwsh"), Some(WindowsShellPreference::Pwsh));
+        assert_eq!(WindowsShellPreference::parse("powershell"), Some(WindowsShellPreference::WindowsPowerShell));
+        assert_eq!(WindowsShellPreference::parse("PowerShell 7"), None);
+    }
 }
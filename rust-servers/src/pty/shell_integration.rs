@@ -0,0 +1,198 @@
+// Shell integration injection
+// Makes supported shells load a script that marks prompts and commands with OSC 133
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const BASH_SCRIPT: &str = include_str!("shell_integration/termy.bash");
+const FISH_SCRIPT: &str = include_str!("shell_integration/termy.fish");
+const POWERSHELL_SCRIPT: &str = include_str!("shell_integration/termy.ps1");
+const ZSH_FILES: [(&str, &str); 4] = [
+    (".zshenv", include_str!("shell_integration/zsh/.zshenv")),
+    (".zprofile", include_str!("shell_integration/zsh/.zprofile")),
+    (".zshrc", include_str!("shell_integration/zsh/.zshrc")),
+    (".zlogin", include_str!("shell_integration/zsh/.zlogin")),
+];
+
+/// How to launch a shell so it loads the integration script
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Injection {
+    /// Arguments placed after the login arguments and before the user's arguments
+    pub args: Vec<String>,
+    /// Extra environment variables
+    pub env: Vec<(String, String)>,
+    /// The script loads the login startup files itself, so no login arguments are added
+    pub handles_login: bool,
+}
+
+/// Injection for the given shell program, or None when the shell is not supported
+///
+/// # Parameters
+/// - `shell_program`: Program the shell is launched with
+/// - `login`: Whether login-shell behavior was requested
+/// - `has_user_args`: Whether the user passed their own shell arguments
+/// - `env`: User-provided environment variables
+pub fn prepare(
+    shell_program: &str,
+    login: bool,
+    has_user_args: bool,
+    env: Option<&HashMap<String, String>>,
+) -> Option<Injection> {
+    let name = super::shell::shell_name(shell_program);
+    match name.as_str() {
+        "bash" | "zsh" | "fish" | "pwsh" | "powershell" => {}
+        _ => return None,
+    }
+    // PowerShell stops parsing options at -Command, which would swallow the user's arguments
+    if matches!(name.as_str(), "pwsh" | "powershell") && has_user_args {
+        return None;
+    }
+
+    let dir = scripts_dir()?;
+    Some(injection_for(&name, dir, login, env))
+}
+
+fn injection_for(
+    name: &str,
+    dir: &Path,
+    login: bool,
+    env: Option<&HashMap<String, String>>,
+) -> Injection {
+    let mut injection = Injection {
+        env: vec![("TERMY_SHELL_INTEGRATION".to_string(), "1".to_string())],
+        ..Injection::default()
+    };
+    match name {
+        "bash" => {
+            // bash ignores --rcfile in login mode, so the script loads the login files instead
+            injection.args = vec![
+                "--rcfile".to_string(),
+                dir.join("termy.bash").to_string_lossy().into_owned(),
+            ];
+            if login {
+                injection.env.push(("TERMY_SHELL_LOGIN".to_string(), "1".to_string()));
+                injection.handles_login = true;
+            }
+        }
+        "zsh" => {
+            // zsh reads every startup file from ZDOTDIR; ours forward to the user's
+            let user_zdotdir = env
+                .and_then(|e| e.get("ZDOTDIR").cloned())
+                .or_else(|| std::env::var("ZDOTDIR").ok());
+            if let Some(user_zdotdir) = user_zdotdir {
+                injection.env.push(("TERMY_USER_ZDOTDIR".to_string(), user_zdotdir));
+            }
+            injection.env.push((
+                "ZDOTDIR".to_string(),
+                dir.join("zsh").to_string_lossy().into_owned(),
+            ));
+        }
+        "fish" => {
+            let path = dir.join("termy.fish").to_string_lossy().replace('\'', "\\'");
+            injection.args = vec!["--init-command".to_string(), format!("source '{}'", path)];
+        }
+        _ => {
+            let path = dir.join("termy.ps1").to_string_lossy().replace('\'', "''");
+            injection.args = vec![
+                "-NoExit".to_string(),
+                "-Command".to_string(),
+                format!(". '{}'", path),
+            ];
+        }
+    }
+    injection
+}
+
+/// Directory holding the integration scripts, written once per process
+fn scripts_dir() -> Option<&'static Path> {
+    static DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+    DIR.get_or_init(|| {
+        // Per-user name so shared temp directories do not collide
+        let name = match std::env::var("USER").or_else(|_| std::env::var("USERNAME")) {
+            Ok(user) if !user.is_empty() => format!("termy-shell-integration-{}", user),
+            _ => "termy-shell-integration".to_string(),
+        };
+        let dir = std::env::temp_dir().join(name);
+        match write_scripts(&dir) {
+            Ok(()) => Some(dir),
+            Err(e) => {
+                tracing::error!(target: "termy::pty", "无法写入 shell 集成脚本: {}", e);
+                None
+            }
+        }
+    })
+    .as_deref()
+}
+
+fn write_scripts(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir.join("zsh"))?;
+    fs::write(dir.join("termy.bash"), BASH_SCRIPT)?;
+    fs::write(dir.join("termy.fish"), FISH_SCRIPT)?;
+    fs::write(dir.join("termy.ps1"), POWERSHELL_SCRIPT)?;
+    for (name, content) in ZSH_FILES {
+        fs::write(dir.join("zsh").join(name), content)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_shells_are_noop() {
+        assert_eq!(prepare("/bin/sh", false, false, None), None);
+        assert_eq!(prepare("cmd.exe", false, false, None), None);
+        assert_eq!(prepare("/usr/bin/nu", true, false, None), None);
+    }
+
+    #[test]
+    fn test_bash_uses_rcfile_and_handles_login() {
+        let dir = Path::new("/tmp/integration");
+        let injection = injection_for("bash", dir, false, None);
+        assert_eq!(injection.args, vec!["--rcfile", "/tmp/integration/termy.bash"]);
+        assert!(!injection.handles_login);
+
+        let login = injection_for("bash", dir, true, None);
+        assert!(login.handles_login);
+        assert!(login.env.contains(&("TERMY_SHELL_LOGIN".to_string(), "1".to_string())));
+    }
+
+    #[test]
+    fn test_zsh_forwards_user_zdotdir() {
+        let env = HashMap::from([("ZDOTDIR".to_string(), "/home/test/.config/zsh".to_string())]);
+        let injection = injection_for("zsh", Path::new("/tmp/integration"), true, Some(&env));
+        assert!(injection.args.is_empty());
+        assert!(!injection.handles_login);
+        assert!(injection
+            .env
+            .contains(&("TERMY_USER_ZDOTDIR".to_string(), "/home/test/.config/zsh".to_string())));
+        assert!(injection
+            .env
+            .contains(&("ZDOTDIR".to_string(), "/tmp/integration/zsh".to_string())));
+    }
+
+    #[test]
+    fn test_fish_and_powershell_source_script() {
+        let dir = Path::new("/tmp/it's");
+        let fish = injection_for("fish", dir, false, None);
+        assert_eq!(fish.args, vec!["--init-command", "source '/tmp/it\\'s/termy.fish'"]);
+
+        let pwsh = injection_for("pwsh", dir, false, None);
+        assert_eq!(pwsh.args, vec!["-NoExit", "-Command", ". '/tmp/it''s/termy.ps1'"]);
+    }
+
+    #[test]
+    fn test_powershell_skipped_with_user_args() {
+        assert_eq!(prepare("pwsh", false, true, None), None);
+    }
+
+    #[test]
+    fn test_scripts_are_written() {
+        let dir = scripts_dir().expect("scripts directory");
+        assert!(dir.join("termy.bash").is_file());
+        assert!(dir.join("zsh").join(".zshrc").is_file());
+    }
+}
@@ -0,0 +1,43 @@
+# Termy shell integration for bash
+# Loaded through --rcfile: sources the usual startup files, then emits OSC 133 markers
+
+if [ "${TERMY_SHELL_LOGIN-}" = "1" ]; then
+    unset TERMY_SHELL_LOGIN
+    # --rcfile disables login startup, so load the login files here
+    [ -r /etc/profile ] && . /etc/profile
+    for __termy_file in "$HOME/.bash_profile" "$HOME/.bash_login" "$HOME/.profile"; do
+        if [ -r "$__termy_file" ]; then
+            . "$__termy_file"
+            break
+        fi
+    done
+    unset __termy_file
+else
+    [ -r /etc/bash.bashrc ] && . /etc/bash.bashrc
+    [ -r "$HOME/.bashrc" ] && . "$HOME/.bashrc"
+fi
+
+__termy_first_prompt=1
+
+# Runs first so the exit status is captured before other prompt commands
+__termy_command_end() {
+    local status=$?
+    if [ -z "$__termy_first_prompt" ]; then
+        printf '\033]133;D;%s\007' "$status"
+    fi
+    __termy_first_prompt=
+    return $status
+}
+
+# Runs last so prompts rebuilt by other prompt commands are wrapped too
+__termy_wrap_prompt() {
+    local status=$?
+    case "$PS1" in
+        *'133;B'*) ;;
+        *) PS1='\[\033]133;A\007\]'"$PS1"'\[\033]133;B\007\]' ;;
+    esac
+    return $status
+}
+
+PROMPT_COMMAND="__termy_command_end${PROMPT_COMMAND:+; $PROMPT_COMMAND}; __termy_wrap_prompt"
+PS0="${PS0-}"'\033]133;C\007'
@@ -0,0 +1,21 @@
+# Termy shell integration for fish
+# Loaded through --init-command after the user's config
+
+function __termy_prompt_start --on-event fish_prompt
+    printf '\e]133;A\a'
+end
+
+function __termy_command_executed --on-event fish_preexec
+    printf '\e]133;C\a'
+end
+
+function __termy_command_end --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+end
+
+# Mark where user input begins, right after the prompt
+functions -c fish_prompt __termy_original_prompt
+function fish_prompt
+    __termy_original_prompt
+    printf '\e]133;B\a'
+end
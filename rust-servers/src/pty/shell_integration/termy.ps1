@@ -0,0 +1,26 @@
+# Termy shell integration for PowerShell
+# Dot-sourced after the user's profile; wraps the prompt function with OSC 133 markers
+
+$global:__TermyOriginalPrompt = $function:prompt
+$global:__TermyFirstPrompt = $true
+
+function global:prompt {
+    $success = $?
+    $code = $global:LASTEXITCODE
+    $esc = [char]27
+    $bel = [char]7
+
+    $result = ""
+    if (-not $global:__TermyFirstPrompt) {
+        $status = if ($success) { 0 } elseif ($code) { $code } else { 1 }
+        $result += "$esc]133;D;$status$bel"
+    }
+    $global:__TermyFirstPrompt = $false
+
+    $result += "$esc]133;A$bel"
+    $result += & $global:__TermyOriginalPrompt
+    $result += "$esc]133;B$bel"
+
+    $global:LASTEXITCODE = $code
+    return $result
+}
@@ -0,0 +1,212 @@
+// Shell version probing
+// Runs a shell program once with its version flag, outside any PTY, and picks the version
+// number out of what it prints; for settings screens showing "zsh 5.9".
+
+use super::shell::shell_name;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long a shell may take to print its version before it is killed
+pub const VERSION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Output read from a version probe; a version line is far shorter
+const MAX_OUTPUT_BYTES: usize = 16 * 1024;
+
+/// What a shell program is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellDescription {
+    /// Display name, such as `PowerShell` or `zsh`
+    pub name: String,
+    /// Version number, such as `7.4.1`; `None` if the shell has no version flag or the
+    /// probe failed
+    pub version: Option<String>,
+    /// Why there is no version
+    pub error: Option<String>,
+}
+
+/// Display name and version arguments of a shell program, by its file name
+fn known_shell(name: &str) -> (&str, Option<&'static [&'static str]>) {
+    match name {
+        "bash" => ("bash", Some(&["--version"])),
+        "zsh" => ("zsh", Some(&["--version"])),
+        "fish" => ("fish", Some(&["--version"])),
+        "nu" => ("Nushell", Some(&["--version"])),
+        "tmux" => ("tmux", Some(&["-V"])),
+        "pwsh" => ("PowerShell", Some(&["-Version"])),
+        // Windows PowerShell's -Version selects a version to run instead of printing one
+        "powershell" => (
+            "Windows PowerShell",
+            Some(&["-NoLogo", "-NoProfile", "-NonInteractive", "-Command", "$PSVersionTable.PSVersion.ToString()"]),
+        ),
+        "cmd" => ("Command Prompt", Some(&["/d", "/c", "ver"])),
+        "wsl" => ("WSL", Some(&["--version"])),
+        // sh, dash, ksh, csh and the like have no version flag
+        _ => (name, None),
+    }
+}
+
+/// Describe the shell at `path`, running it through `run` with its version arguments
+///
+/// `run` returns what the program printed; [`run_version_command`] in the server.
+pub fn describe(path: &Path, run: impl FnOnce(&Path, &[&str]) -> Result<Vec<u8>, String>) -> ShellDescription {
+    let file_name = shell_name(&path.to_string_lossy());
+    let (name, args) = known_shell(&file_name);
+    let name = name.to_string();
+    let Some(args) = args else {
+        return ShellDescription { name, version: None, error: Some("shell has no version flag".to_string()) };
+    };
+    match run(path, args) {
+        Ok(output) => {
+            let version = parse_version(&decode(&output));
+            let error = version.is_none().then(|| "no version number in the output".to_string());
+            ShellDescription { name, version, error }
+        }
+        Err(e) => ShellDescription { name, version: None, error: Some(e) },
+    }
+}
+
+/// Run a program with `args` and no input, returning its output (stdout, or stderr if
+/// stdout is empty); killed after [`VERSION_TIMEOUT`]
+pub fn run_version_command(path: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let mut command = Command::new(path);
+    command.args(args).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // No console window flashing up for a probe
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    let mut child = command.spawn().map_err(|e| format!("failed to run shell: {}", e))?;
+
+    let deadline = Instant::now() + VERSION_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err("shell did not print its version in time".to_string());
+            }
+            Err(e) => return Err(format!("failed to wait for shell: {}", e)),
+        }
+    }
+
+    let output = read_pipe(child.stdout.take())?;
+    if !output.is_empty() {
+        return Ok(output);
+    }
+    read_pipe(child.stderr.take())
+}
+
+fn read_pipe(pipe: Option<impl Read>) -> Result<Vec<u8>, String> {
+    let mut output = Vec::new();
+    if let Some(pipe) = pipe {
+        pipe.take(MAX_OUTPUT_BYTES as u64)
+            .read_to_end(&mut output)
+            .map_err(|e| format!("failed to read shell output: {}", e))?;
+    }
+    Ok(output)
+}
+
+/// Output as text; Windows tools such as wsl.exe print UTF-16
+fn decode(output: &[u8]) -> String {
+    let nuls = output.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+    if output.len() >= 2 && nuls * 2 >= output.len() / 2 {
+        let units: Vec<u16> = output.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+        return String::from_utf16_lossy(&units);
+    }
+    String::from_utf8_lossy(output).into_owned()
+}
+
+/// First version number in the output: digits and dots with at least one dot, and a
+/// trailing letter as in tmux's `3.3a`
+fn parse_version(output: &str) -> Option<String> {
+    let bytes = output.as_bytes();
+    let mut start = 0;
+    while start < bytes.len() {
+        let at_word_start = start == 0 || !bytes[start - 1].is_ascii_alphanumeric();
+        if !(bytes[start].is_ascii_digit() && at_word_start) {
+            start += 1;
+            continue;
+        }
+        let mut end = start;
+        while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b'.') {
+            end += 1;
+        }
+        let number = output[start..end].trim_end_matches('.');
+        if number.contains('.') {
+            // A single letter right after the digits, not the start of a word
+            let letter = if number.len() == end - start
+                && bytes.get(end).is_some_and(u8::is_ascii_lowercase)
+                && !bytes.get(end + 1).is_some_and(u8::is_ascii_alphanumeric)
+            {
+                &output[end..end + 1]
+            } else {
+                ""
+            };
+            return Some(format!("{}{}", number, letter));
+        }
+        start = end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describe_with_output(path: &str, output: &str) -> ShellDescription {
+        describe(Path::new(path), |_, _| Ok(output.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn test_parses_common_version_output() {
+        let bash = describe_with_output("/bin/bash", "GNU bash, version 5.2.15(1)-release (x86_64-pc-linux-gnu)\nCopyright (C) 2022");
+        assert_eq!(bash, ShellDescription { name: "bash".to_string(), version: Some("5.2.15".to_string()), error: None });
+        assert_eq!(describe_with_output("/usr/bin/zsh", "zsh 5.9 (x86_64-ubuntu-linux-gnu)\n").version.as_deref(), Some("5.9"));
+        assert_eq!(describe_with_output("/usr/bin/tmux", "tmux 3.3a\n").version.as_deref(), Some("3.3a"));
+        assert_eq!(describe_with_output("/usr/bin/fish", "fish, version 3.7.0\n").version.as_deref(), Some("3.7.0"));
+
+        let pwsh = describe_with_output("C:\\Program Files\\PowerShell\\7\\pwsh.exe", "PowerShell 7.4.1\r\n");
+        assert_eq!((pwsh.name.as_str(), pwsh.version.as_deref()), ("PowerShell", Some("7.4.1")));
+        let cmd = describe_with_output("C:\\Windows\\System32\\cmd.exe", "\r\nMicrosoft Windows [Version 10.0.22631.4169]\r\n");
+        assert_eq!((cmd.name.as_str(), cmd.version.as_deref()), ("Command Prompt", Some("10.0.22631.4169")));
+    }
+
+    #[test]
+    fn test_decodes_utf16_output() {
+        let output: Vec<u8> = "WSL version: 2.1.5.0\r\n".encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect();
+        let wsl = describe(Path::new("C:\\Windows\\System32\\wsl.exe"), |_, _| Ok(output));
+        assert_eq!(wsl.version.as_deref(), Some("2.1.5.0"));
+    }
+
+    #[test]
+    fn test_passes_version_arguments() {
+        let mut seen = Vec::new();
+        describe(Path::new("/usr/bin/tmux"), |_, args| {
+            seen = args.iter().map(|arg| arg.to_string()).collect();
+            Ok(Vec::new())
+        });
+        assert_eq!(seen, ["-V"]);
+    }
+
+    #[test]
+    fn test_shell_without_version_flag_is_not_run() {
+        let dash = describe(Path::new("/bin/dash"), |_, _| panic!("dash has no version flag"));
+        assert_eq!(dash.name, "dash");
+        assert_eq!(dash.version, None);
+        assert!(dash.error.is_some());
+    }
+
+    #[test]
+    fn test_failed_or_unparsable_probe_has_no_version() {
+        let failed = describe(Path::new("/bin/zsh"), |_, _| Err("timed out".to_string()));
+        assert_eq!((failed.version, failed.error.as_deref()), (None, Some("timed out")));
+        assert_eq!(describe_with_output("/bin/zsh", "zsh: unknown option\n").version, None);
+        assert_eq!(parse_version("build 12 of v2"), None);
+    }
+}
@@ -0,0 +1,48 @@
+// Output sinks
+// Where a connection's PTY output and events go. The handler only sends binary frames
+// (output) and text frames (JSON events), so any transport providing both will do: the
+// WebSocket, a local socket or an in-process channel.
+
+use async_trait::async_trait;
+use futures_util::SinkExt;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Transport of one connection
+#[async_trait]
+pub trait OutputSink: Send {
+    /// Send a binary frame: terminal output, see the frame module for its layout
+    async fn send_binary(&mut self, data: Vec<u8>) -> io::Result<()>;
+
+    /// Send a text frame: a JSON-encoded event
+    async fn send_text(&mut self, text: String) -> io::Result<()>;
+}
+
+/// Sink shared by a connection's sessions
+///
+/// The lock serializes sends; holding it keeps other output behind a replay.
+pub type SharedSink = Arc<TokioMutex<dyn OutputSink>>;
+
+type WsSink = futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, Message>;
+
+#[async_trait]
+impl OutputSink for WsSink {
+    async fn send_binary(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.send(Message::Binary(data.into())).await.map_err(io::Error::other)
+    }
+
+    async fn send_text(&mut self, text: String) -> io::Result<()> {
+        self.send(Message::Text(text.into())).await.map_err(io::Error::other)
+    }
+}
+
+/// Send a frame built by the handler; frames other than binary and text are never built
+pub async fn deliver(sink: &mut dyn OutputSink, message: Message) -> io::Result<()> {
+    match message {
+        Message::Binary(data) => sink.send_binary(data.into()).await,
+        Message::Text(text) => sink.send_text(text.to_string()).await,
+        _ => Ok(()),
+    }
+}
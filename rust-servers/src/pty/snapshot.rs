@@ -0,0 +1,174 @@
+// Session list snapshots
+// What a connection's sessions were, as JSON a client keeps across a server restart; only
+// metadata, the processes themselves do not survive. Imported records come back as
+// placeholders that restart starts again under the same id.
+
+use serde::{Deserialize, Serialize};
+
+/// Format of [`SessionSnapshot`]; bumped on incompatible changes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Sessions of one connection at export time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub version: u32,
+    /// Epoch milliseconds of the export
+    pub exported_at_ms: u64,
+    pub sessions: Vec<SessionRecord>,
+}
+
+/// Metadata of one session, enough to start it again
+///
+/// The environment is left out on purpose: it often carries credentials, and a snapshot
+/// is handed to the client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// shell_type requested at init; `None` for the default shell
+    #[serde(default)]
+    pub shell_type: Option<String>,
+    /// Shell type that was actually launched, informational
+    #[serde(default)]
+    pub resolved_shell_type: Option<String>,
+    #[serde(default)]
+    pub shell_args: Option<Vec<String>>,
+    /// Program and arguments of a `command` session
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// Working directory the shell was started in
+    #[serde(default)]
+    pub cwd: Option<String>,
+    pub cols: u16,
+    pub rows: u16,
+    /// Epoch milliseconds the session was created at
+    #[serde(default)]
+    pub created_at_ms: u64,
+}
+
+impl SessionSnapshot {
+    /// Parse a snapshot a client sent back
+    pub fn from_value(value: serde_json::Value) -> Result<Self, String> {
+        let snapshot: Self = serde_json::from_value(value).map_err(|e| format!("invalid snapshot: {}", e))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "unsupported snapshot version {} (expected {})",
+                snapshot.version, SNAPSHOT_VERSION
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+impl SessionRecord {
+    /// Fields of an init message that starts the session again
+    pub fn init_payload(&self) -> serde_json::Value {
+        let mut payload = serde_json::json!({
+            "session_id": self.session_id,
+            "cols": self.cols,
+            "rows": self.rows,
+        });
+        let fields = payload.as_object_mut().expect("payload is an object");
+        match self.command.as_deref() {
+            Some([program, args @ ..]) => {
+                fields.insert("command".to_string(), serde_json::json!(program));
+                fields.insert("args".to_string(), serde_json::json!(args));
+            }
+            _ => {
+                if let Some(shell_type) = &self.shell_type {
+                    fields.insert("shell_type".to_string(), serde_json::json!(shell_type));
+                }
+                if let Some(shell_args) = &self.shell_args {
+                    fields.insert("shell_args".to_string(), serde_json::json!(shell_args));
+                }
+            }
+        }
+        for (name, value) in [("label", &self.label), ("group", &self.group), ("cwd", &self.cwd)] {
+            if let Some(value) = value {
+                fields.insert(name.to_string(), serde_json::json!(value));
+            }
+        }
+        payload
+    }
+
+    /// The record as a session_list entry of a session that has to be restarted
+    pub fn list_entry(&self) -> serde_json::Value {
+        serde_json::json!({
+            "session_id": self.session_id,
+            "label": self.label,
+            "group": self.group,
+            "needs_restart": true,
+            "shell_type": self.shell_type,
+            "resolved_shell_type": self.resolved_shell_type,
+            "cwd": self.cwd,
+            "cols": self.cols,
+            "rows": self.rows,
+            "created_at_ms": self.created_at_ms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(session_id: &str) -> SessionRecord {
+        SessionRecord {
+            session_id: session_id.to_string(),
+            label: Some("build".to_string()),
+            group: None,
+            shell_type: Some("custom:/bin/sh".to_string()),
+            resolved_shell_type: Some("custom".to_string()),
+            shell_args: Some(vec!["-i".to_string()]),
+            command: None,
+            cwd: Some("/tmp".to_string()),
+            cols: 100,
+            rows: 30,
+            created_at_ms: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trips_through_json() {
+        let snapshot = SessionSnapshot {
+            version: SNAPSHOT_VERSION,
+            exported_at_ms: 1_700_000_100_000,
+            sessions: vec![record("a"), record("b")],
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed = SessionSnapshot::from_value(serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(parsed, snapshot);
+    }
+
+    #[test]
+    fn test_rejects_other_versions_and_malformed_snapshots() {
+        let value = serde_json::json!({ "version": SNAPSHOT_VERSION + 1, "exported_at_ms": 0, "sessions": [] });
+        assert!(SessionSnapshot::from_value(value).unwrap_err().contains("version"));
+        let value = serde_json::json!({ "version": SNAPSHOT_VERSION, "exported_at_ms": 0, "sessions": [{}] });
+        assert!(SessionSnapshot::from_value(value).is_err());
+    }
+
+    #[test]
+    fn test_init_payload_restores_the_launch() {
+        let payload = record("a").init_payload();
+        assert_eq!(payload["session_id"], "a");
+        assert_eq!(payload["shell_type"], "custom:/bin/sh");
+        assert_eq!(payload["shell_args"], serde_json::json!(["-i"]));
+        assert_eq!((payload["cols"].as_u64(), payload["rows"].as_u64()), (Some(100), Some(30)));
+        assert!(payload.get("group").is_none());
+
+        let command = SessionRecord {
+            shell_type: None,
+            shell_args: None,
+            command: Some(vec!["make".to_string(), "-j4".to_string()]),
+            ..record("b")
+        };
+        let payload = command.init_payload();
+        assert_eq!(payload["command"], "make");
+        assert_eq!(payload["args"], serde_json::json!(["-j4"]));
+        assert!(payload.get("shell_type").is_none());
+    }
+}
@@ -0,0 +1,75 @@
+// Output tap
+// A copy of a session's PTY output for external tools, written to a local endpoint: a
+// Unix domain socket, or a named pipe on Windows. The WebSocket stream is unaffected.
+
+use std::path::{Component, Path};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Output chunks queued for a tap that reads slowly before it is dropped
+const TAP_QUEUE_LEN: usize = 256;
+
+/// Check a tap path against the prefix the server allows taps under
+///
+/// The path comes from the client, so without a prefix (see `PtyConfig::tap_prefix`)
+/// taps are refused: output must not be sent to an arbitrary socket of the server user.
+pub fn check_path(path: &str, allowed_prefix: Option<&str>) -> Result<(), String> {
+    let Some(prefix) = allowed_prefix else {
+        return Err("tap_socket is not enabled on this server".to_string());
+    };
+    let escapes = Path::new(path).components().any(|component| component == Component::ParentDir);
+    if !path.starts_with(prefix) || path.len() == prefix.len() || escapes {
+        return Err(format!("tap_socket must be a path under {}", prefix));
+    }
+    Ok(())
+}
+
+/// Connection to a tap endpoint
+///
+/// Output is queued for a task that writes it, so a slow reader never holds up the
+/// session; a tap that falls [`TAP_QUEUE_LEN`] chunks behind is dropped instead.
+pub struct Tap {
+    queue: mpsc::Sender<Vec<u8>>,
+}
+
+impl Tap {
+    /// Connect to the endpoint; the tap only writes, whatever the other side sends is ignored
+    pub async fn connect(path: &str) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        let mut stream = tokio::net::UnixStream::connect(path).await?;
+        #[cfg(windows)]
+        let mut stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path)?;
+
+        let (queue, mut chunks) = mpsc::channel::<Vec<u8>>(TAP_QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(chunk) = chunks.recv().await {
+                if stream.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self { queue })
+    }
+
+    /// Queue a copy of output; an error means the tap is gone or too slow and should be dropped
+    pub fn send(&self, data: &[u8]) -> Result<(), &'static str> {
+        self.queue.try_send(data.to_vec()).map_err(|e| match e {
+            TrySendError::Full(_) => "tap is not keeping up",
+            TrySendError::Closed(_) => "tap endpoint went away",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("/run/taps/one.sock", None).is_err());
+        assert!(check_path("/run/taps/one.sock", Some("/run/taps/")).is_ok());
+        assert!(check_path("/run/taps/", Some("/run/taps/")).is_err());
+        assert!(check_path("/run/other.sock", Some("/run/taps/")).is_err());
+        assert!(check_path("/run/taps/../other.sock", Some("/run/taps/")).is_err());
+    }
+}
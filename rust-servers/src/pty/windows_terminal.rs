@@ -0,0 +1,177 @@
+// Windows Terminal settings
+// Finds the program of the profile Windows Terminal opens by default, so the default shell
+// of a session matches what the user gets in a new terminal tab.
+
+use std::path::{Path, PathBuf};
+
+/// Profile source of PowerShell 7 installs, which Windows Terminal generates without a
+/// command line
+const POWERSHELL_CORE_SOURCE: &str = "Windows.Terminal.PowershellCore";
+
+/// settings.json locations under `%LOCALAPPDATA%`, stable release first
+pub fn settings_paths(local_app_data: &Path) -> Vec<PathBuf> {
+    let packaged = ["Microsoft.WindowsTerminal_8wekyb3d8bbwe", "Microsoft.WindowsTerminalPreview_8wekyb3d8bbwe"]
+        .iter()
+        .map(|package| local_app_data.join("Packages").join(package).join("LocalState").join("settings.json"));
+    // Unpackaged installs (scoop, portable zip)
+    let unpackaged = local_app_data.join("Microsoft").join("Windows Terminal").join("settings.json");
+    packaged.chain(std::iter::once(unpackaged)).collect()
+}
+
+/// Program of the default profile in the first settings file found, as written there
+/// (environment variables not expanded)
+pub fn default_profile_program() -> Option<String> {
+    default_profile_program_in(Path::new(&std::env::var_os("LOCALAPPDATA")?))
+}
+
+/// [`default_profile_program`] for the given `%LOCALAPPDATA%`
+pub fn default_profile_program_in(local_app_data: &Path) -> Option<String> {
+    let text = settings_paths(local_app_data)
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())?;
+    default_program(&text)
+}
+
+/// Program of the default profile in a settings.json text
+///
+/// `None` when the default profile cannot be found, has a command line with arguments
+/// (a bare program is all the default shell can be), or is generated for something other
+/// than PowerShell 7, such as a WSL distribution.
+pub fn default_program(settings: &str) -> Option<String> {
+    let settings: serde_json::Value = serde_json::from_str(&strip_jsonc(settings)).ok()?;
+    let default = settings.get("defaultProfile")?.as_str()?;
+    let profiles = settings.get("profiles")?;
+    // Either a plain list or an object with defaults and a list
+    let list = profiles.as_array().or_else(|| profiles.get("list")?.as_array())?;
+    let profile = list.iter().find(|profile| {
+        let field = |name: &str| profile.get(name).and_then(|value| value.as_str());
+        field("guid").is_some_and(|guid| guid.eq_ignore_ascii_case(default)) || field("name") == Some(default)
+    })?;
+
+    match profile.get("commandline").and_then(|value| value.as_str()) {
+        Some(commandline) => match super::shell::parse_custom_command(commandline).ok()?.as_slice() {
+            [program] => Some(program.clone()),
+            _ => None,
+        },
+        None => (profile.get("source").and_then(|value| value.as_str()) == Some(POWERSHELL_CORE_SOURCE))
+            .then(|| "pwsh".to_string()),
+    }
+}
+
+/// Turn Windows Terminal's JSON with comments and trailing commas into plain JSON
+fn strip_jsonc(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        out.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = '\0';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            ('}' | ']', _) => {
+                // Drop a comma left before the closing bracket
+                let kept = out.trim_end().len();
+                if out[..kept].ends_with(',') {
+                    out.truncate(kept - 1);
+                }
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: &str = r#"
+    // This file was initially generated by Windows Terminal
+    {
+        "$schema": "https://aka.ms/terminal-profiles-schema",
+        "defaultProfile": "{574e775e-4f2a-5b96-ac1e-a2962a402336}",
+        "profiles": {
+            "defaults": {},
+            "list": [
+                {
+                    "guid": "{61c54bbd-c2c6-5271-96e7-009a87ff44bf}",
+                    "name": "Windows PowerShell",
+                    "commandline": "%SystemRoot%\\System32\\WindowsPowerShell\\v1.0\\powershell.exe", /* built in */
+                },
+                {
+                    "guid": "{574E775E-4F2A-5B96-AC1E-A2962A402336}",
+                    "name": "PowerShell // 7",
+                    "source": "Windows.Terminal.PowershellCore",
+                },
+                {
+                    "guid": "{2c4de342-38b7-51cf-b940-2309a097f518}",
+                    "name": "Ubuntu",
+                    "source": "Windows.Terminal.Wsl",
+                },
+            ],
+        },
+    }
+    "#;
+
+    fn with_default(default: &str) -> String {
+        SETTINGS.replace("{574e775e-4f2a-5b96-ac1e-a2962a402336}", default)
+    }
+
+    #[test]
+    fn test_reads_default_profile_through_comments_and_trailing_commas() {
+        // Matched regardless of case, and by a generated profile's source
+        assert_eq!(default_program(SETTINGS).as_deref(), Some("pwsh"));
+        assert_eq!(
+            default_program(&with_default("Windows PowerShell")).as_deref(),
+            Some("%SystemRoot%\\System32\\WindowsPowerShell\\v1.0\\powershell.exe")
+        );
+    }
+
+    #[test]
+    fn test_skips_profiles_that_are_not_a_bare_program() {
+        assert_eq!(default_program(&with_default("{2c4de342-38b7-51cf-b940-2309a097f518}")), None);
+        assert_eq!(default_program(&with_default("{00000000-0000-0000-0000-000000000000}")), None);
+        let with_args = SETTINGS.replace(
+            "\"source\": \"Windows.Terminal.PowershellCore\"",
+            "\"commandline\": \"pwsh.exe -NoExit -Command Import-Module Tools\"",
+        );
+        assert_eq!(default_program(&with_args), None);
+        assert_eq!(default_program("not json"), None);
+    }
+
+    #[test]
+    fn test_settings_paths_start_with_the_stable_release() {
+        let paths = settings_paths(Path::new("C:\\Users\\test\\AppData\\Local"));
+        assert!(paths[0].to_string_lossy().contains("Microsoft.WindowsTerminal_8wekyb3d8bbwe"));
+        assert!(paths.last().unwrap().ends_with("settings.json"));
+    }
+}
@@ -131,18 +131,38 @@ pub enum RouterError {
     
     /// Invalid message format
     #[error("Invalid message format: {0}")]
-    #[allow(dead_code)]
     InvalidMessage(String),
     
     /// Module handling error
     #[error("Module error: {0}")]
     ModuleError(String),
+
+    /// Module error with a machine-readable code
+    ///
+    /// `details` must be a JSON object; its fields are merged into the error payload
+    #[error("{code}: {message}")]
+    Coded {
+        code: &'static str,
+        message: String,
+        details: serde_json::Value,
+    },
     
     /// JSON serialization/deserialization error
     #[error("JSON error: {0}")]
     JsonError(#[from] serde_json::Error),
 }
 
+impl RouterError {
+    /// Create a coded error
+    pub fn coded(code: &'static str, message: impl Into<String>, details: serde_json::Value) -> Self {
+        RouterError::Coded {
+            code,
+            message: message.into(),
+            details,
+        }
+    }
+}
+
 // ============================================================================
 // Module handler trait
 // ============================================================================
@@ -178,10 +198,20 @@ pub struct MessageRouter {
 impl MessageRouter {
     /// Create a new message router
     pub fn new() -> Self {
+        Self::with_pty_config(crate::pty::PtyConfig::default())
+    }
+
+    /// Create a new message router with a custom PTY configuration
+    pub fn with_pty_config(config: crate::pty::PtyConfig) -> Self {
         Self {
-            pty_handler: crate::pty::PtyHandler::new(),
+            pty_handler: crate::pty::PtyHandler::with_config(config),
         }
     }
+
+    /// Create a new message router around an existing PTY handler
+    pub fn with_pty_handler(pty_handler: crate::pty::PtyHandler) -> Self {
+        Self { pty_handler }
+    }
     
     /// Set the WebSocket sender (used for PTY output)
     pub async fn set_ws_sender(&self, sender: WsSender) {
@@ -243,6 +273,18 @@ impl MessageRouter {
             RouterError::UnknownModule(m) => ("UNKNOWN_MODULE", format!("未知模块: {}", m)),
             RouterError::InvalidMessage(m) => ("INVALID_MESSAGE", format!("无效消息: {}", m)),
             RouterError::ModuleError(m) => ("MODULE_ERROR", m.clone()),
+            RouterError::Coded { code, message, details } => {
+                let mut response = ServerResponse::error(module, code, message);
+                if let (Some(payload), Some(details)) = (response.payload.as_object_mut(), details.as_object()) {
+                    for (key, value) in details {
+                        // code and message always describe the error itself
+                        if key != "code" && key != "message" {
+                            payload.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+                return response;
+            }
             RouterError::JsonError(e) => ("JSON_ERROR", format!("JSON 错误: {}", e)),
         };
         
@@ -380,6 +422,22 @@ mod tests {
         assert_eq!(payload.get("message").unwrap().as_str().unwrap(), "Something went wrong");
     }
     
+    #[test]
+    fn test_create_error_response_coded() {
+        let router = MessageRouter::new();
+        let error = RouterError::coded(
+            "SESSION_NOT_FOUND",
+            "会话不存在: abc",
+            serde_json::json!({ "session_id": "abc", "code": "ignored" }),
+        );
+        let response = router.create_error_response(ModuleType::Pty, &error);
+
+        assert_eq!(response.msg_type, "error");
+        assert_eq!(response.payload["code"], "SESSION_NOT_FOUND");
+        assert_eq!(response.payload["message"], "会话不存在: abc");
+        assert_eq!(response.payload["session_id"], "abc");
+    }
+    
     #[test]
     fn test_pty_module_is_implemented() {
         let router = MessageRouter::new();
@@ -6,7 +6,9 @@ use tokio_tungstenite::{accept_async, tungstenite::Message};
 use futures_util::{StreamExt, SinkExt};
 use std::sync::Arc;
 use tokio::sync::Mutex as TokioMutex;
+use tokio::time::{self, Duration, MissedTickBehavior};
 
+use crate::pty::{PtyConfig, PtyHandler, SessionDirectory};
 use crate::router::{MessageRouter, ModuleType, RouterError, ServerResponse};
 
 /// Logging macro
@@ -35,8 +37,26 @@ macro_rules! log_debug {
 // ============================================================================
 
 /// WebSocket server configuration
+#[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub port: u16,
+    /// PTY handler configuration applied to every connection
+    pub pty: PtyConfig,
+    /// Interval between server pings; zero disables the keepalive
+    pub ping_interval: Duration,
+    /// Number of consecutive unanswered pings after which the connection is treated as dead
+    pub max_missed_pongs: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 0,
+            pty: PtyConfig::default(),
+            ping_interval: Duration::from_secs(20),
+            max_missed_pongs: 3,
+        }
+    }
 }
 
 /// WebSocket server
@@ -51,6 +71,7 @@ impl Server {
 
     /// Start the server
     pub async fn start(&self) -> Result<u16, Box<dyn std::error::Error>> {
+        crate::pty::set_windows_shell_preference(self.config.pty.windows_shell);
         let addr = format!("127.0.0.1:{}", self.config.port);
         let listener = TcpListener::bind(&addr).await?;
         let local_addr = listener.local_addr()?;
@@ -67,12 +88,17 @@ impl Server {
         );
 
         // Main loop: accept WebSocket connections
+        let config = self.config.clone();
+        // Shared by all connections so sessions can be watched from another connection
+        let directory = Arc::new(SessionDirectory::default());
         tokio::spawn(async move {
             log_info!("正在监听 WebSocket 连接...");
             while let Ok((stream, addr)) = listener.accept().await {
                 log_debug!("接受来自 {} 的连接", addr);
+                let config = config.clone();
+                let directory = Arc::clone(&directory);
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream).await {
+                    if let Err(e) = handle_connection(stream, config, directory).await {
                         log_error!("连接处理错误: {}", e);
                     }
                 });
@@ -96,6 +122,8 @@ pub type WsSender = Arc<TokioMutex<futures_util::stream::SplitSink<
 /// Handle a single WebSocket connection
 async fn handle_connection(
     stream: tokio::net::TcpStream,
+    config: ServerConfig,
+    directory: Arc<SessionDirectory>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Upgrade to WebSocket
     let ws_stream = accept_async(stream).await?;
@@ -107,13 +135,45 @@ async fn handle_connection(
     let ws_sender: WsSender = Arc::new(TokioMutex::new(ws_sender));
     
     // Create the message router
-    let router = Arc::new(MessageRouter::new());
+    let pty_handler = PtyHandler::with_directory(config.pty.clone(), directory);
+    let router = Arc::new(MessageRouter::with_pty_handler(pty_handler));
     
     // Set the WebSocket sender (used for PTY output)
     router.set_ws_sender(Arc::clone(&ws_sender)).await;
+
+    // Keepalive: a client that silently dropped off never answers pings
+    let keepalive_enabled = !config.ping_interval.is_zero();
+    let mut ping_timer = time::interval(if keepalive_enabled {
+        config.ping_interval
+    } else {
+        Duration::from_secs(3600)
+    });
+    ping_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ping_timer.reset();
+    let mut missed_pongs: u32 = 0;
     
     // Message handling loop
-    while let Some(msg_result) = ws_receiver.next().await {
+    loop {
+        let msg_result = tokio::select! {
+            msg_result = ws_receiver.next() => match msg_result {
+                Some(msg_result) => msg_result,
+                None => break,
+            },
+            _ = ping_timer.tick(), if keepalive_enabled => {
+                if missed_pongs >= config.max_missed_pongs {
+                    log_info!("连续 {} 次未收到 Pong，视为连接已断开", missed_pongs);
+                    break;
+                }
+                missed_pongs += 1;
+                let mut sender = ws_sender.lock().await;
+                if let Err(e) = sender.send(Message::Ping(Vec::new().into())).await {
+                    log_error!("发送 Ping 失败: {}", e);
+                    break;
+                }
+                continue;
+            }
+        };
+
         match msg_result {
             Ok(msg) => {
                 log_debug!("收到消息类型: {:?}", std::mem::discriminant(&msg));
@@ -130,34 +190,10 @@ async fn handle_connection(
                         }
                     }
                     Message::Binary(data) => {
-                        // Binary data, written to the PTY
-                        // Format: [session_id_length: u8][session_id: bytes][data: bytes]
+                        // Binary input, written to the PTY as-is
                         log_debug!("收到二进制数据: {} 字节", data.len());
-                        
-                        if data.len() < 2 {
-                            log_error!("二进制数据格式错误: 数据太短");
-                            continue;
-                        }
-                        
-                        let session_id_len = data[0] as usize;
-                        if data.len() < 1 + session_id_len {
-                            log_error!("二进制数据格式错误: session_id 长度不足");
-                            continue;
-                        }
-                        
-                        let session_id = match std::str::from_utf8(&data[1..1 + session_id_len]) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                log_error!("二进制数据格式错误: session_id 不是有效 UTF-8: {}", e);
-                                continue;
-                            }
-                        };
-                        
-                        let pty_data = &data[1 + session_id_len..];
-                        log_debug!("写入 PTY: session_id={}, {} 字节", session_id, pty_data.len());
-                        
-                        if let Err(e) = router.pty_handler().write_data(session_id, pty_data).await {
-                            log_error!("写入 PTY 失败: session_id={}, {}", session_id, e);
+                        if let Err(e) = router.pty_handler().handle_binary(&data).await {
+                            log_error!("写入 PTY 失败: {}", e);
                         }
                     }
                     Message::Close(_) => {
@@ -170,7 +206,8 @@ async fn handle_connection(
                         sender.send(Message::Pong(data)).await?;
                     }
                     Message::Pong(_) => {
-                        // Ignore Pong
+                        // The client is alive
+                        missed_pongs = 0;
                     }
                     _ => {
                         log_debug!("忽略的消息类型");
@@ -291,3 +328,61 @@ pub async fn send_binary(
     sender.send(Message::Binary(data.into())).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    async fn serve_one(config: ServerConfig) -> (tokio::task::JoinHandle<()>, std::net::SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, config, Arc::new(SessionDirectory::default())).await.unwrap();
+        });
+        (task, addr)
+    }
+
+    fn keepalive_config(ping_interval: Duration) -> ServerConfig {
+        ServerConfig {
+            ping_interval,
+            max_missed_pongs: 2,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_disconnected_after_missed_pongs() {
+        let (task, addr) = serve_one(keepalive_config(Duration::from_millis(20))).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        // The client never reads, so pings are never answered
+        let (_client, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap();
+
+        time::timeout(Duration::from_secs(2), task)
+            .await
+            .expect("connection should be dropped")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_responsive_client_stays_connected() {
+        let (task, addr) = serve_one(keepalive_config(Duration::from_millis(20))).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (client, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap();
+        let (mut client_sender, mut client_receiver) = client.split();
+
+        // Reading drives the automatic pong replies
+        let reader = tokio::spawn(async move { while client_receiver.next().await.is_some() {} });
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!task.is_finished());
+
+        client_sender.send(Message::Close(None)).await.unwrap();
+        time::timeout(Duration::from_secs(2), task).await.unwrap().unwrap();
+        reader.abort();
+    }
+}
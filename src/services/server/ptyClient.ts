@@ -19,6 +19,41 @@ import { debugLog, errorLog } from '@/utils/logger';
 type SessionEventHandler<K extends keyof SessionEventListeners> =
   SessionEventListeners[K] extends Set<infer Handler> ? Handler : never;
 
+/** Binary frame format version, the first byte of every frame */
+const FRAME_VERSION = 1;
+
+/** Bytes before the session ID: version and big-endian u16 length */
+const FRAME_HEADER_LENGTH = 3;
+
+/** Set in the version byte of output frames whose payload is raw deflate data */
+const FRAME_FLAG_DEFLATE = 0x80;
+
+/** Whether this runtime can inflate compressed output frames */
+function canInflate(): boolean {
+  return typeof DecompressionStream !== 'undefined';
+}
+
+/** Inflate a raw deflate payload */
+async function inflateRaw(data: Uint8Array): Promise<Uint8Array> {
+  const stream = new Blob([data]).stream().pipeThrough(new DecompressionStream('deflate-raw'));
+  return new Uint8Array(await new Response(stream).arrayBuffer());
+}
+
+/**
+ * Build a binary PTY frame
+ *
+ * Frame format: [version: u8 = 1][session_id_length: u16 big-endian][session_id: bytes][data: bytes]
+ */
+function encodeFrame(sessionId: string, data: Uint8Array): Uint8Array {
+  const sessionIdBytes = new TextEncoder().encode(sessionId);
+  const frame = new Uint8Array(FRAME_HEADER_LENGTH + sessionIdBytes.length + data.length);
+  frame[0] = FRAME_VERSION;
+  new DataView(frame.buffer).setUint16(1, sessionIdBytes.length);
+  frame.set(sessionIdBytes, FRAME_HEADER_LENGTH);
+  frame.set(data, FRAME_HEADER_LENGTH + sessionIdBytes.length);
+  return frame;
+}
+
 /**
  * PTY module client
  */
@@ -32,6 +67,10 @@ export class PtyClient extends ModuleClient {
   /** Temporarily stores the init request ID for response correlation */
   private pendingInitId: string | null = null;
 
+  /** Output waiting behind a frame that is being inflated, emitted in arrival order */
+  private outputQueue: Promise<void> = Promise.resolve();
+  private queuedOutputs = 0;
+
   constructor() {
     super('pty');
   }
@@ -86,6 +125,8 @@ export class PtyClient extends ModuleClient {
         env: config.env,
         cols: config.cols,
         rows: config.rows,
+        compress_output: config.compress_output && canInflate(),
+        encoding: config.encoding,
       });
     });
   }
@@ -111,17 +152,8 @@ export class PtyClient extends ModuleClient {
     if (!this.ws || this.ws.readyState !== WebSocket.OPEN) {
       return;
     }
-    // PTY text input: format [session_id_length][session_id][data]
-    const sessionIdBytes = new TextEncoder().encode(sessionId);
-    const dataBytes = new TextEncoder().encode(data);
-    
-    // Build the binary frame
-    const frame = new Uint8Array(1 + sessionIdBytes.length + dataBytes.length);
-    frame[0] = sessionIdBytes.length;
-    frame.set(sessionIdBytes, 1);
-    frame.set(dataBytes, 1 + sessionIdBytes.length);
-    
-    this.ws.send(frame);
+    // PTY text input, sent as a binary frame
+    this.ws.send(encodeFrame(sessionId, new TextEncoder().encode(data)));
   }
 
   /**
@@ -135,16 +167,8 @@ export class PtyClient extends ModuleClient {
       return;
     }
     
-    const sessionIdBytes = new TextEncoder().encode(sessionId);
     const dataArray = data instanceof ArrayBuffer ? new Uint8Array(data) : data;
-    
-    // Build the binary frame
-    const frame = new Uint8Array(1 + sessionIdBytes.length + dataArray.length);
-    frame[0] = sessionIdBytes.length;
-    frame.set(sessionIdBytes, 1);
-    frame.set(dataArray, 1 + sessionIdBytes.length);
-    
-    this.ws.send(frame);
+    this.ws.send(encodeFrame(sessionId, dataArray));
   }
 
   /**
@@ -367,33 +391,53 @@ export class PtyClient extends ModuleClient {
    * Handle binary messages (PTY output)
    * Called by ServerManager
    * 
-   * Frame format: [session_id_length: u8][session_id: bytes][data: bytes]
+   * Frame format: [version: u8 = 1][session_id_length: u16 big-endian][session_id: bytes][data: bytes]
+   * With FRAME_FLAG_DEFLATE set in the version byte, data is raw deflate
    */
   handleBinaryMessage(data: ArrayBuffer): void {
     const bytes = new Uint8Array(data);
     
-    if (bytes.length < 2) {
+    if (bytes.length < FRAME_HEADER_LENGTH) {
       errorLog('[PtyClient] 二进制消息太短');
       return;
     }
+    const compressed = (bytes[0] & FRAME_FLAG_DEFLATE) !== 0;
+    if ((bytes[0] & ~FRAME_FLAG_DEFLATE) !== FRAME_VERSION) {
+      errorLog(`[PtyClient] 不支持的二进制帧版本: ${bytes[0]}`);
+      return;
+    }
     
     // Parse session_id
-    const sessionIdLength = bytes[0];
-    if (bytes.length < 1 + sessionIdLength) {
+    const sessionIdEnd = FRAME_HEADER_LENGTH + new DataView(data).getUint16(1);
+    if (bytes.length < sessionIdEnd) {
       errorLog('[PtyClient] 二进制消息格式错误: session_id 长度不足');
       return;
     }
     
-    const sessionIdBytes = bytes.slice(1, 1 + sessionIdLength);
+    const sessionIdBytes = bytes.slice(FRAME_HEADER_LENGTH, sessionIdEnd);
     const sessionId = new TextDecoder().decode(sessionIdBytes);
     
     // Extract data
-    const outputData = bytes.slice(1 + sessionIdLength);
+    const outputData = bytes.slice(sessionIdEnd);
     
     debugLog(`[PtyClient] 收到会话 ${sessionId} 的输出, 长度: ${outputData.length}`);
     
-    // Emit the session-scoped event
-    this.emitSessionOutput(sessionId, outputData);
+    if (!compressed && this.queuedOutputs === 0) {
+      // Emit the session-scoped event
+      this.emitSessionOutput(sessionId, outputData);
+      return;
+    }
+
+    // Inflating is asynchronous; later frames wait so output stays in order
+    this.queuedOutputs++;
+    this.outputQueue = this.outputQueue
+      .then(async () => {
+        this.emitSessionOutput(sessionId, compressed ? await inflateRaw(outputData) : outputData);
+      })
+      .catch((error) => errorLog('[PtyClient] 解压输出失败:', error))
+      .finally(() => {
+        this.queuedOutputs--;
+      });
   }
 
   /**
@@ -135,6 +135,10 @@ export interface PtyConfig {
   cols?: number;
   /** Rows */
   rows?: number;
+  /** Ask the server to deflate large output frames (sent only if this runtime can inflate them) */
+  compress_output?: boolean;
+  /** Output encoding hint (e.g. "gbk"); the server keeps forwarding raw bytes */
+  encoding?: string;
 }
 
 /**